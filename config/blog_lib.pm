@@ -38,7 +38,8 @@ sub parse_link_cache {
   my $row = 0;
   while (<$link_handle>) {
     $row += 1;
-    if ($_ =~ /^([^,]*),([^,]*),(.*)$/) {
+    # Old caches do not have the word count and reading time at the end
+    if ($_ =~ /^([^,]*),([^,]*),(.*?)(?:,\d+,\d+)?$/) {
       $links{$1 . $2} = $3;
     } else {
       die "Link cache has invalid line\n$row: $_\n";
@@ -81,6 +81,7 @@ local_templates_dir="$( api_lookuP "local_templates_dir" "$@" )" || exit 1
   local_output_path="$( api_lookuP "local_output_path" "$@" )" || exit 1
 relative_output_url="$( api_lookuP "relative_output_url" "$@" )" || exit 1
    other_view_langs="$( api_lookuP "other_view_langs" "$@" )" || exit 1
+       fallback_for="$( api_lookuP "fallback_for" "$@" )" || exit 1
 
 
 # @VOLATILE: sync with left aside on changes
@@ -111,6 +112,7 @@ date_updated="${date_updated%??????????????}"
   <meta name="viewport" content="width=device-width, initial-scale=1.0">
   <meta http-equiv="X-UA-Compatible" content="ie=edge">
   <title>${title}</title>
+$( [ -n "${fallback_for}" ] && printf %s '  <meta name="robots" content="noindex">' )
   <link rel="stylesheet" href="${domain}/style.css">
   <!--<script type="text/javascript"></script>
   <script type="text/javascript" src="src/app.js"></script>
@@ -183,11 +185,14 @@ $( spaces="      "
           # @FORMAT
           path="$( awk -v FS=',' -v id="${id}" -v lang="${lang}" '
             $1 == id && $2 == lang {
-              printf "%s", $3;
-              # In case title has commas, print them
+              path = $3;
+              # In case path has commas, print them
               for (i = 4; i <= NF; ++i) {
-                printf ",%s", $(i);
+                path = path "," $(i);
               }
+              # Drop the word count and reading time (absent in old caches)
+              sub(/,[0-9]+,[0-9]+$/, "", path);
+              printf "%s", path;
             }
           ' "${link_cache}" )"
 
@@ -225,7 +230,9 @@ $( cat "${local_toc_path}" )
   <main>
     <h1>${title}</h1>
     <div>Last Updated: ${date_updated}</div>
-$( cat "${local_doc_path}" )
+$( [ -n "${fallback_for}" ] \
+  && printf %s\\n "    <p>This post is not available in ${fallback_for}. See 'Other Languages'.</p>"
+  cat "${local_doc_path}" )
   </main>
   <footer>
 <!-- INSERT: footer -->
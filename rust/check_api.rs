@@ -0,0 +1,271 @@
+// The 'check-api' subcommand
+// Runs a handler through the protocol (see 'fileapi.rs') on sample posts so
+// that a new handler can be tested before a real build fails on it. Only the
+// language markers are in the handler's markup (they use its comment marker),
+// so handlers have to cope with posts that are just plain paragraphs.
+
+use crate::{
+    fileapi::{Context, FileApi, Handler, Limits},
+    frontmatter::Frontmatter,
+    post::{LangGroups, Post, API_SET_LANGUAGE},
+    traits::ShellEscape,
+};
+use chrono::Utc;
+use std::{borrow::Cow, fs, path::Path};
+
+// '{set}' is replaced with the language marker, e.g. '//api_set_lang:'
+const SAMPLES: [&str; 3] = [
+    "A sample post without any language markers.\n",
+    "\
+{set} en jp
+A paragraph shared by English and Japanese.
+{set} en
+An English only paragraph.
+{set} jp
+日本語だけの段落。
+{set} ALL
+A paragraph for every language.
+",
+    "\
+{set} zh
+中文段落。
+
+{set} en
+Two
+
+paragraphs.
+",
+];
+
+// Returns (report, number of failed checks)
+pub fn check(
+    api_dir: &str,
+    handler: &Handler,
+    limits: Limits,
+    temp_dir: &Path,
+) -> Result<(String, usize), String> {
+    let mut api = FileApi::from_handler(api_dir, handler, limits)?;
+    api.capture_stderr(); // Command errors include it, other failures add it
+    let mut report = Report::default();
+    report.buffer.push_str(
+        format!(
+            "Checking {} (capabilities: {})\n",
+            api.handler().to_string_lossy().escape(),
+            api.capabilities().join(" "),
+        )
+        .as_str(),
+    );
+
+    let comment = api.comment();
+    let stderr = api.take_stderr();
+    let comment = match comment {
+        Ok(comment) if comment.is_empty() => {
+            let reason = with_stderr("the comment marker is empty", &stderr);
+            report.fail("comment", reason.as_str());
+            return Ok(report.finish());
+        }
+        Ok(comment) if comment.contains(['\n', '\r']) => {
+            let reason = ["the comment marker is not one line: ", &comment.escape()].join("");
+            report.fail("comment", with_stderr(&reason, &stderr).as_str());
+            return Ok(report.finish());
+        }
+        Ok(comment) => {
+            report.pass("comment", comment.escape().as_str());
+            comment
+        }
+        Err(err) => {
+            report.fail("comment", err.as_str());
+            return Ok(report.finish());
+        }
+    };
+
+    fs::create_dir_all(temp_dir).map_err(|err| {
+        [
+            "Cannot create directory ",
+            temp_dir.to_string_lossy().escape().as_str(),
+            ".\n",
+            err.to_string().as_str(),
+        ]
+        .join("")
+    })?;
+    let cache_dir = temp_dir.to_string_lossy();
+    for (i, sample) in SAMPLES.iter().enumerate() {
+        let text = sample.replace(
+            "{set}",
+            [comment.as_str(), API_SET_LANGUAGE].join("").as_str(),
+        );
+        let post_id = format!("sample{}", i + 1);
+        let post = match Post::new(
+            text.as_str(),
+            comment.as_str(),
+            None,
+            &LangGroups::default(),
+        ) {
+            Ok(post) => post,
+            Err(err) => {
+                let err = err.with_filename(Cow::Borrowed(post_id.as_str()));
+                report.fail(post_id.as_str(), err.to_string().as_str());
+                continue;
+            }
+        };
+
+        for view in &post.views {
+            let lang = view.lang.unwrap_or("");
+            let other_langs = post.lang_list.iter().filter(|l| **l != lang);
+            let other_langs = other_langs.copied().collect::<Vec<_>>().join(" ");
+            let context = Context {
+                lang,
+                post_id: post_id.as_str(),
+                source_path: post_id.as_str(),
+                other_langs: other_langs.as_str(),
+                cache_dir: &cache_dir,
+                lines: Some((view, &post.origins)),
+            };
+            let name = [post_id.as_str(), " ", lang].join("");
+            let name = name.trim_end();
+
+            let step = [name, " frontmatter"].join("");
+            let result = api.frontmatter(view.body.as_slice(), &context);
+            let stderr = api.take_stderr();
+            match result {
+                _ if !api.supports("frontmatter") => {
+                    report.pass(step.as_str(), "skipped, no 'frontmatter' capability")
+                }
+                Ok(output) => match Frontmatter::new(output.as_str(), Utc::now(), Utc::now()) {
+                    Ok(_) => report.pass(step.as_str(), ""),
+                    Err(err) => {
+                        let err = err.with_filename(Cow::Borrowed(step.as_str()));
+                        let reason = [
+                            err.to_string().as_str(),
+                            "\n=== STDOUT ===\n",
+                            output.as_str(),
+                        ]
+                        .join("");
+                        report.fail(step.as_str(), with_stderr(&reason, &stderr).as_str());
+                    }
+                },
+                Err(err) => report.fail(step.as_str(), err.as_str()),
+            }
+
+            let step = [name, " compile"].join("");
+            let file_stem = [post_id.as_str(), "-", lang].join("");
+            let toc_path = temp_dir.join([file_stem.as_str(), ".toc.html"].join(""));
+            let doc_path = temp_dir.join([file_stem.as_str(), ".doc.html"].join(""));
+            let _ = fs::remove_file(&toc_path);
+            let _ = fs::remove_file(&doc_path);
+            let toc_loc = toc_path.to_string_lossy();
+            let doc_loc = doc_path.to_string_lossy();
+            let result = api.compile(
+                view.body.as_slice(),
+                "http://localhost",
+                &toc_loc,
+                &doc_loc,
+                &context,
+            );
+            let stderr = api.take_stderr();
+            match result {
+                Ok(_) if !toc_path.is_file() => {
+                    let reason = ["the TOC was not written to ", &toc_loc.escape()].join("");
+                    report.fail(step.as_str(), with_stderr(&reason, &stderr).as_str());
+                }
+                Ok(_) if !doc_path.is_file() => {
+                    let reason = ["the doc was not written to ", &doc_loc.escape()].join("");
+                    report.fail(step.as_str(), with_stderr(&reason, &stderr).as_str());
+                }
+                Ok(_) => report.pass(step.as_str(), ""),
+                Err(err) => report.fail(step.as_str(), err.as_str()),
+            }
+        }
+    }
+    let _ = fs::remove_dir_all(temp_dir);
+    Ok(report.finish())
+}
+
+// For failures other than the handler exiting with an error, which already
+// has STDERR in its message
+fn with_stderr(reason: &str, stderr: &str) -> String {
+    if stderr.is_empty() {
+        reason.to_string()
+    } else {
+        [reason.trim_end(), "\n=== STDERR ===\n", stderr].join("")
+    }
+}
+
+#[derive(Default)]
+struct Report {
+    buffer: String,
+    failed: usize,
+    count: usize,
+}
+
+impl Report {
+    fn pass(&mut self, step: &str, detail: &str) {
+        self.count += 1;
+        self.buffer.push_str("ok   ");
+        self.buffer.push_str(step);
+        if !detail.is_empty() {
+            self.buffer.push_str(": ");
+            self.buffer.push_str(detail);
+        }
+        self.buffer.push('\n');
+    }
+
+    fn fail(&mut self, step: &str, reason: &str) {
+        self.count += 1;
+        self.failed += 1;
+        self.buffer.push_str("FAIL ");
+        self.buffer.push_str(step);
+        self.buffer.push_str(": ");
+        self.buffer.push_str(reason.trim_end());
+        self.buffer.push('\n');
+    }
+
+    fn finish(mut self) -> (String, usize) {
+        self.buffer
+            .push_str(format!("{} of {} checks failed\n", self.failed, self.count).as_str());
+        (self.buffer, self.failed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fileapi::HandlerMap;
+    use crate::helpers::TempDir;
+
+    fn check_handler(name: &str) -> (String, usize) {
+        let handler = HandlerMap::default().resolve(name);
+        let temp_dir = TempDir::new("check-api");
+        check("tests/api", &handler, Limits::default(), &temp_dir.0).unwrap()
+    }
+
+    #[test]
+    fn passing_handler() {
+        let (report, failed) = check_handler("check-passes");
+        assert_eq!(failed, 0, "{}", report);
+        assert!(report.contains("ok   sample2 jp compile\n"), "{}", report);
+        assert!(!report.contains("a warning"), "{}", report);
+    }
+
+    #[test]
+    fn failing_handler() {
+        let (report, failed) = check_handler("check-fails");
+        assert!(failed > 0, "{}", report);
+        assert!(report.contains("ok   comment: '//'\n"), "{}", report);
+        // Both when the handler errors and when it exits fine but is wrong
+        let frontmatter = "FAIL sample1 frontmatter: Error while executing ";
+        assert!(report.contains(frontmatter), "{}", report);
+        assert!(
+            report.contains("=== STDERR ===\ncannot parse\n"),
+            "{}",
+            report
+        );
+        let compile = "FAIL sample1 compile: the TOC was not written to ";
+        assert!(report.contains(compile), "{}", report);
+        assert!(
+            report.contains("=== STDERR ===\nskipped writing "),
+            "{}",
+            report
+        );
+    }
+}
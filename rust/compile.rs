@@ -15,10 +15,19 @@ use std::{borrow::Cow, collections::HashMap, fs, io::Read, path::Path};
 use super::RequiredConfigs;
 use crate::{
     custom_errors::ParseError,
-    fileapi::{command_run, FileApi},
+    fileapi::{command_run, stderr_prefix, Context, FileApi, HandlerMap, Limits, Stderr},
     frontmatter::{Frontmatter, Value},
-    helpers::{create_parent_dir, PathReadMetadata},
-    post::Post,
+    helpers::{
+        create_parent_dir, parse_num_option, parse_seconds_option, push_json_list, push_json_str,
+        PathReadMetadata,
+    },
+    highlight,
+    include::{self, Origins},
+    post::{LangGroups, Post},
+    related,
+    store::{self, Store},
+    text,
+    timings::{label, Stage, Timings},
     traits::{BoolExt, ResultExt, ShellEscape, VecExt},
 };
 
@@ -32,12 +41,11 @@ macro_rules! zip {
 // @TODO Add spacing between different compile steps, make a print vec function
 // @TODO support for light and dark modes
 // @TODO cli subcommand for verify valid url links
-// @TODO validate url for output_format, post ids
+// @TODO validate url for post ids
 // @TODO add validation that series labels do not have invalid characters
-// @TODO add default language
 // @TODO figure out api for labeling series
 // @TODO fix random symlink images/images in make.sh
-// @TODO async, probably want profiling first?
+// @TODO async, see --timings for profiling
 
 //run: ../make.sh build-rust build
 // run: cargo test helpers -- --nocapture
@@ -52,8 +60,12 @@ type Shared<'config, 'path_list, 'input_path, 'shared> = (
 // Using macro so we get around ownership and self-reference placement
 macro_rules! shared_metadata {
     (let ($changelog:ident, $shared:ident, $lang_list:ident, $api:pat, $post_list:pat)
-      = from($config:ident, $input_list:ident)
+      = from($config:ident, $input_list:ident, $timings:ident)
     ) => {
+        // Fail before running any handlers if the options are malformed
+        validate_output_formats($config).or_die(1);
+        validate_fallback_stubs($config).or_die(1);
+
         // Read the 'input_list' into 'changelog' and 'text_list'
         let mut log_owner = String::new();
         #[allow(unused_mut)]
@@ -66,11 +78,15 @@ macro_rules! shared_metadata {
                 .map_err(|err| err.with_filename(Cow::Borrowed(log_loc)))
                 .or_die(1)
         };
-        let text_list = {
+        let mut text_list = {
             let mut text_list = Vec::with_capacity($input_list.len());
             for path in $input_list {
                 let mut text = String::new();
-                read_file(path.path, &mut text).or_die(1);
+                $timings.time(
+                    Stage::Read,
+                    || path.path.to_string_lossy().to_string(),
+                    || read_file(path.path, &mut text).or_die(1),
+                );
                 text_list.push_and_check(text);
             }
             text_list
@@ -79,38 +95,68 @@ macro_rules! shared_metadata {
         // Parse into Post
         // 'text_list', 'shared_metadata', 'lang_list', 'log_owner' are owned
         // the rest are one-time use or borrow from these sources
-        let (shared_metadata, $lang_list, $api, $post_list) =
-            shared_view_metadata_new($config, &text_list, &$changelog, $input_list);
+        let (shared_metadata, $lang_list, $api, $post_list) = shared_view_metadata_new(
+            $config,
+            &mut text_list,
+            &$changelog,
+            $input_list,
+            &mut $timings,
+        );
         let $shared = ($config, $input_list, shared_metadata.as_slice());
     };
 }
 
-
 pub fn build(config: &RequiredConfigs, input_list: &[PathReadMetadata]) {
+    let mut timings = Timings::new(config.timings);
+
     // Read files and parse into Post
     shared_metadata!(
         let (changelog, shared, lang_list, api, post_list)
-        = from(config, input_list)
+        = from(config, input_list, timings)
     );
 
+    // Parse and verify the frontmatter
+    // Done before compiling so that we can error before running the handlers
+    let mut linker_metadata = linker_metadata_new(shared, &lang_list);
+    check_output_collisions(shared, &linker_metadata).or_die(1);
+
     // Run the markup compiler
-    htmlify_into_partials(shared, &mut changelog, api, post_list);
+    let compiled = htmlify_into_partials(shared, &mut changelog, api, post_list, &mut timings);
+    highlight_partials(shared, &compiled, &mut timings);
     // We can drop 'text_list', 'post_list', and 'api' here
-
-    // Parse and verify the frontmatter
-    let linker_metadata = linker_metadata_new(shared, &lang_list);
+    doc_metadata_new(shared, &mut linker_metadata);
 
     // Must update the cache before linking as linker uses this info
-    write_caches(shared, &changelog, &linker_metadata, UPDATE);
+    let (mut old_tags, mut old_series) = (String::new(), String::new());
+    let _ = read_file(Path::new(config.tags_cache.as_str()), &mut old_tags);
+    let _ = read_file(Path::new(config.series_cache.as_str()), &mut old_series);
+    write_caches(shared, &changelog, &linker_metadata, UPDATE, &mut timings);
+    related_posts_new(
+        shared,
+        &mut linker_metadata,
+        Some((old_tags.as_str(), old_series.as_str())),
+    );
 
     // Link/Join the partials into the final output
-    join_partials(shared, &changelog, &linker_metadata);
+    join_partials(shared, &changelog, &linker_metadata, &mut timings);
+
+    if config.output_store {
+        let max_size = parse_num_option(config.output_store_size, "output-store-size")
+            .or_die(1)
+            .unwrap_or(store::DEFAULT_MAX_SIZE);
+        let (count, _) = Store::new(&config.store_dir).prune(max_size).or_die(1);
+        if config.verbose && count > 0 {
+            eprintln!("Evicted {} files from the output store", count);
+        }
+    }
+    report_timings(config, &timings);
 }
 
 pub fn delete(config: &RequiredConfigs, input_list: &[PathReadMetadata]) {
+    let mut timings = Timings::new(config.timings);
     shared_metadata!(
         let (changelog, shared, lang_list, _, _)
-        = from(config, input_list)
+        = from(config, input_list, timings)
     );
     let linker_metadata = linker_metadata_new(shared, &lang_list);
 
@@ -142,7 +188,8 @@ pub fn delete(config: &RequiredConfigs, input_list: &[PathReadMetadata]) {
     for path in input_list {
         changelog.remove(path);
     }
-    write_caches(shared, &changelog, &linker_metadata, DELETE);
+    write_caches(shared, &changelog, &linker_metadata, DELETE, &mut timings);
+    report_timings(config, &timings);
 }
 
 pub fn relink(config: &RequiredConfigs, input_list: &[PathReadMetadata]) {
@@ -151,12 +198,27 @@ pub fn relink(config: &RequiredConfigs, input_list: &[PathReadMetadata]) {
         temp.force = true;
         temp
     };
+    let mut timings = Timings::new(config.timings);
     shared_metadata!(
         let (changelog, shared, lang_list, _, _)
-        = from(config, input_list)
+        = from(config, input_list, timings)
     );
-    let linker_metadata = linker_metadata_new(shared, &lang_list);
-    join_partials(shared, &changelog, &linker_metadata);
+    let mut linker_metadata = linker_metadata_new(shared, &lang_list);
+    check_output_collisions(shared, &linker_metadata).or_die(1);
+    doc_metadata_new(shared, &mut linker_metadata);
+    related_posts_new(shared, &mut linker_metadata, None);
+    join_partials(shared, &changelog, &linker_metadata, &mut timings);
+    report_timings(config, &timings);
+}
+
+fn report_timings(config: &RequiredConfigs, timings: &Timings) {
+    if timings.is_enabled() {
+        eprint!("{}", timings.report());
+        if config.timings_csv {
+            eprintln!("Saving timings to {}", config.timings_cache.escape());
+            write_file(config.timings_cache.as_str(), timings.to_csv().as_str()).or_die(1);
+        }
+    }
 }
 
 
@@ -173,8 +235,22 @@ struct ViewMetadata {
     post_lang_count: usize,
     toc_loc: String,
     doc_loc: String,
+    excerpt_marker: Option<String>, // See 'api_excerpt:' in 'Post::new()'
+    is_fallback: bool,              // Stub for a missing site language
+}
+
+// Posts are grouped by handler (see '--handler-map'), not by extension, so
+// that aliased extensions share one handler (and one persistent process)
+struct ApiAndComment<'input_path> {
+    handler_of: HashMap<&'input_path str, usize>, // Extension to 'apis' index
+    apis: Vec<(FileApi, String)>,
+}
+
+impl<'input_path> ApiAndComment<'input_path> {
+    fn get(&self, extension: &str) -> Option<&(FileApi, String)> {
+        self.handler_of.get(extension).map(|i| &self.apis[*i])
+    }
 }
-type ApiAndComment<'input_path> = HashMap<&'input_path str, (FileApi, String)>;
 
 // Using this so that we can discard 'api_and_comment' and 'text_list'
 struct ViewMetadataWalker<'a> {
@@ -221,38 +297,96 @@ impl<'a> Iterator for ViewMetadataWalker<'a> {
 }
 
 fn shared_view_metadata_new<'config, 'text, 'input_path>(
-    config: &'config RequiredConfigs,
-    text_list: &'text [String],
+    config: &RequiredConfigs<'config>,
+    text_list: &'text mut [String],
     changelog: &UpdateTimes,
     input_paths: &[PathReadMetadata<'input_path>],
+    timings: &mut Timings,
 ) -> (
     Vec<ViewMetadata>,
     Vec<String>,
     ApiAndComment<'input_path>,
     Vec<Post<'text>>,
-) {
+)
+where
+    'config: 'text, // 'default_lang' is borrowed by 'Post'
+{
     debug_assert_eq!(text_list.len(), input_paths.len());
 
     let len = input_paths.len();
 
     // Two-part builder, 'api_and_comment' is shared between both
     // Bulid 'post_list'
-    let mut api_and_comment = HashMap::new();
+    let mut api_and_comment = ApiAndComment {
+        handler_of: HashMap::new(),
+        apis: Vec::new(),
+    };
     let mut post_list = Vec::with_capacity(len);
     let mut views_count = 0;
-    for (path, text) in zip!(input_paths, text_list) {
+    let limits = limits(config, config.handler_timeout, "handler-timeout");
+    let store = config.output_store.then(|| Store::new(&config.store_dir));
+    let handler_map = HandlerMap::read(config.handler_map).or_die(1);
+    let lang_groups = LangGroups::parse(config.lang_groups).or_die(1);
+    let mut handlers = Vec::new(); // Parallel to 'api_and_comment.apis'
+    let mut includes_updated = Vec::with_capacity(len); // Parallel to 'post_list'
+    for (path, text) in input_paths.iter().zip(text_list.iter_mut()) {
         let extension = path.extension;
-        if !api_and_comment.contains_key(extension) {
-            let api = FileApi::from_filename(config.api_dir, extension).or_die(1);
-            let comment = api.comment().or_die(1);
-            api_and_comment.insert(extension, (api, comment));
+        if !api_and_comment.handler_of.contains_key(extension) {
+            let handler = handler_map.resolve(extension);
+            let index = match handlers.iter().position(|h| *h == handler) {
+                Some(index) => index,
+                None => {
+                    let api = FileApi::from_handler(config.api_dir, &handler, limits).or_die(1);
+                    let comment = api.comment().or_die(1);
+                    api_and_comment.apis.push((api, comment));
+                    handlers.push(handler);
+                    handlers.len() - 1
+                }
+            };
+            if config.verbose {
+                let api = &api_and_comment.apis[index].0;
+                let mut handler = api.handler().to_string_lossy().escape();
+                for arg in &handlers[index].args {
+                    handler.push(' ');
+                    handler.push_str(arg.escape().as_str());
+                }
+                eprintln!("Using {} for .{}", handler, extension);
+            }
+            api_and_comment.handler_of.insert(extension, index);
         }
         let (_, comment) = api_and_comment.get(extension).unwrap();
-        let post = Post::new(text, comment.as_str())
-            .map_err(|err| err.with_filename(path.path.to_string_lossy()))
+
+        // Splice in 'api_include:' files before splitting by language
+        let spliced = timings.time(
+            Stage::Read,
+            || path.path.to_string_lossy().to_string(),
+            || include::splice(text, comment.as_str(), path.path).or_die(1),
+        );
+        let origins = if let Some((spliced, updated, origins)) = spliced {
+            *text = spliced;
+            includes_updated.push(Some(updated));
+            origins
+        } else {
+            includes_updated.push(None);
+            Origins::default()
+        };
+        let text: &'text String = text;
+        let mut post = timings
+            .time(
+                Stage::Split,
+                || path.path.to_string_lossy().to_string(),
+                || Post::new(text, comment.as_str(), config.default_lang, &lang_groups),
+            )
+            .map_err(|err| {
+                err.with_source_row(|row| {
+                    let (file, row) = origins.locate(row, path.path);
+                    (file.to_string_lossy(), row)
+                })
+            })
             .or_die(1);
+        post.origins = origins;
 
-        views_count += post.views.len();
+        views_count += post.views.len() + fallback_langs(config, &post).len();
         post_list.push_and_check(post);
     }
 
@@ -260,25 +394,92 @@ fn shared_view_metadata_new<'config, 'text, 'input_path>(
     // This is independent of 'text_list' lifetime
     let mut shared_metadata = Vec::with_capacity(views_count);
     let mut lang_list = Vec::with_capacity(len);
-    for (path, post) in zip!(input_paths, post_list) {
+    for (i, (path, post)) in zip!(input_paths, post_list).enumerate() {
         let (api, _) = api_and_comment.get(path.extension).unwrap();
-        let lang_list_string = post.lang_list.join(" ");
+        let is_outdated = changelog.check_if_outdated(path)
+            || includes_updated[i]
+                .map(|updated| changelog.check_if_outdated_since(path, updated))
+                .unwrap_or(false);
+
+        // Stubs go after the real views so that the 'view_index' of the real
+        // views still index into 'post.views'
+        let fallback_langs = fallback_langs(config, post);
+        let mut lang_list_string = post.lang_list.join(" ");
+        for lang in &fallback_langs {
+            lang_list_string.push(' ');
+            lang_list_string.push_str(lang);
+        }
+        let post_lang_count = post.lang_list.len() + fallback_langs.len();
 
         let mut from = 0;
         for (j, view) in post.views.iter().enumerate() {
-            let frontmatter_string = api.frontmatter(view.body.as_slice()).or_die(1);
             let lang_str = view.lang.unwrap_or("");
+            let other_langs = other_langs(post, lang_str);
+            let source_path = path.path.to_string_lossy();
+            let context = Context {
+                lang: lang_str,
+                post_id: path.stem,
+                source_path: &source_path,
+                other_langs: other_langs.as_str(),
+                cache_dir: config.cache_dir,
+                lines: Some((view, &post.origins)),
+            };
+            let body = view.body.as_slice();
+            let stored = store
+                .as_ref()
+                .map(|store| (store, Store::key(api, &["frontmatter"], &context, body)));
+            let frontmatter_string = stored
+                .as_ref()
+                .filter(|_| !config.force)
+                .and_then(|(store, key)| store.get_frontmatter(key))
+                .unwrap_or_else(|| {
+                    let frontmatter = timings
+                        .time(
+                            Stage::Frontmatter,
+                            || label(path.stem, lang_str, &api.handler().to_string_lossy()),
+                            || api.frontmatter(body, &context),
+                        )
+                        .map_err(|err| while_processing(path.stem, lang_str, err))
+                        .or_die(1);
+                    if let Some((store, key)) = &stored {
+                        store.put_frontmatter(key, &frontmatter).or_die(1);
+                    }
+                    frontmatter
+                });
             let lang_range = from..from + lang_str.len();
             debug_assert_eq!(lang_str, &lang_list_string[lang_range.clone()]);
 
             shared_metadata.push_and_check(ViewMetadata {
                 view_index: j,
-                is_outdated: changelog.check_if_outdated(path),
+                is_outdated,
                 frontmatter_string,
-                post_lang_count: post.lang_list.len(),
+                post_lang_count,
                 lang: lang_range,
                 toc_loc: [config.cache_dir, "/toc/", lang_str, "/", path.stem, ".html"].join(""),
                 doc_loc: [config.cache_dir, "/doc/", lang_str, "/", path.stem, ".html"].join(""),
+                excerpt_marker: view.excerpt.map(text::collapse_whitespace),
+                is_fallback: false,
+            });
+
+            from += lang_str.len() + ' '.len_utf8();
+        }
+
+        // Stubs borrow the frontmatter (e.g. title) of the first view
+        let first_view = shared_metadata.len() - post.views.len();
+        for (j, lang_str) in fallback_langs.iter().enumerate() {
+            let lang_range = from..from + lang_str.len();
+            debug_assert_eq!(*lang_str, &lang_list_string[lang_range.clone()]);
+
+            shared_metadata.push_and_check(ViewMetadata {
+                view_index: post.views.len() + j,
+                is_outdated,
+                frontmatter_string: shared_metadata[first_view].frontmatter_string.clone(),
+                post_lang_count,
+                lang: lang_range,
+                toc_loc: [config.cache_dir, "/toc/", lang_str, "/", path.stem, ".html"].join(""),
+                doc_loc: [config.cache_dir, "/doc/", lang_str, "/", path.stem, ".html"].join(""),
+                excerpt_marker: None,
+                is_fallback: true,
             });
 
             from += lang_str.len() + ' '.len_utf8();
@@ -289,6 +490,48 @@ fn shared_view_metadata_new<'config, 'text, 'input_path>(
     (shared_metadata, lang_list, api_and_comment, post_list)
 }
 
+// The '--site-langs' that 'post' is missing if '--fallback-stubs' is set
+// Posts without languages are not translations so do not get stubs
+fn fallback_langs<'config>(config: &RequiredConfigs<'config>, post: &Post) -> Vec<&'config str> {
+    match config.site_langs {
+        Some(site_langs) if config.fallback_stubs && !post.lang_list.is_empty() => site_langs
+            .split_whitespace()
+            .filter(|lang| !post.lang_list.contains(lang))
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn limits(config: &RequiredConfigs, timeout: Option<&str>, long: &str) -> Limits {
+    Limits {
+        timeout: parse_seconds_option(timeout, long).or_die(1),
+        max_output: parse_num_option(config.max_output, "max-output").or_die(1),
+    }
+}
+
+// Names the post and view for errors from the handler or linker
+fn while_processing(id: &str, lang: &str, err: String) -> String {
+    [
+        "While processing the post ",
+        id.escape().as_str(),
+        " (view ",
+        if lang.is_empty() {
+            "without a language"
+        } else {
+            lang
+        },
+        ")\n",
+        err.as_str(),
+    ]
+    .join("")
+}
+
+// The languages of 'post' other than 'lang', space separated
+fn other_langs(post: &Post, lang: &str) -> String {
+    let others = post.lang_list.iter().filter(|l| **l != lang);
+    others.copied().collect::<Vec<_>>().join(" ")
+}
+
 /******************************************************************************/
 // Compile step
 // HTMLify the post (i.e. run through asciidoctor, etc.)
@@ -299,14 +542,18 @@ fn htmlify_into_partials<'input_path, 'log>(
     changelog: &mut UpdateTimes<'log>,
     api_and_comment: ApiAndComment,
     post_list: Vec<Post>, // Eat this
-) where
+    timings: &mut Timings,
+) -> Vec<usize>
+where
     'input_path: 'log,
 {
     debug_assert_eq!(input_list.len(), post_list.len());
 
     // Because we flatten post views, using cursor to
     let mut buffer = String::new();
-    for (_, j, is_new_post, _, view_data) in walk(shared_metadata) {
+    let mut compiled = Vec::new(); // Indices into 'shared_metadata'
+    let store = config.output_store.then(|| Store::new(&config.store_dir));
+    for (i, j, is_new_post, _, view_data) in walk(shared_metadata) {
         let path = &input_list[j];
         if is_new_post {
             [path.stem, ".", path.extension]
@@ -317,6 +564,17 @@ fn htmlify_into_partials<'input_path, 'log>(
         let toc_loc = view_data.toc_loc.as_str();
         let doc_loc = view_data.doc_loc.as_str();
 
+        // Stubs have no content for the handler, just empty partials
+        if view_data.is_fallback {
+            if config.force || !Path::new(toc_loc).exists() || !Path::new(doc_loc).exists() {
+                create_parent_dir(toc_loc).or_die(1);
+                create_parent_dir(doc_loc).or_die(1);
+                write_file(toc_loc, "").or_die(1);
+                write_file(doc_loc, "").or_die(1);
+            }
+            continue;
+        }
+
         if config.force
             || view_data.is_outdated
             || !Path::new(toc_loc).exists()
@@ -324,17 +582,55 @@ fn htmlify_into_partials<'input_path, 'log>(
         {
             let (api, _) = api_and_comment.get(path.extension).unwrap();
             let view = &post_list[j].views[view_data.view_index];
+            let lang_str = view.lang.unwrap_or("");
+            let other_langs = other_langs(&post_list[j], lang_str);
+            let source_path = path.path.to_string_lossy();
+            let context = Context {
+                lang: lang_str,
+                post_id: path.stem,
+                source_path: &source_path,
+                other_langs: other_langs.as_str(),
+                cache_dir: config.cache_dir,
+                lines: Some((view, &post_list[j].origins)),
+            };
 
             // @TODO: Create directories in building api cache (less work)
             create_parent_dir(toc_loc).or_die(1);
             create_parent_dir(doc_loc).or_die(1);
-            api.compile(view.body.as_slice(), config.domain, toc_loc, doc_loc)
-                .or_die(1);
+            let body = view.body.as_slice();
+            let stored = store.as_ref().map(|store| {
+                let args = ["compile", config.domain];
+                (store, Store::key(api, &args, &context, body))
+            });
+            let is_restored = stored
+                .as_ref()
+                .filter(|_| !config.force)
+                .map(|(store, key)| store.restore_partials(key, toc_loc, doc_loc))
+                .unwrap_or(false);
+            if !is_restored {
+                timings
+                    .time(
+                        Stage::Compile,
+                        || label(path.stem, lang_str, &api.handler().to_string_lossy()),
+                        || api.compile(body, config.domain, toc_loc, doc_loc, &context),
+                    )
+                    .map_err(|err| while_processing(path.stem, lang_str, err))
+                    .or_die(1);
+                if let Some((store, key)) = &stored {
+                    store.save_partials(key, toc_loc, doc_loc).or_die(1);
+                }
+            }
 
             changelog.update(path);
+            compiled.push(i);
 
-            if config.verbose {
-                eprintln!("Compiling {} to", buffer);
+            if config.verbose && is_restored {
+                eprintln!("Restoring {} from the output store to", buffer);
+                eprintln!("- {}", toc_loc.escape());
+                eprintln!("- {}", doc_loc.escape());
+            } else if config.verbose {
+                let handler = api.handler().to_string_lossy();
+                eprintln!("Compiling {} with {} to", buffer, handler.escape());
                 eprintln!("- {}", toc_loc.escape());
                 eprintln!("- {}", doc_loc.escape());
             } else if is_new_post {
@@ -344,6 +640,32 @@ fn htmlify_into_partials<'input_path, 'log>(
             eprintln!("Skipping compile of {} (use --force to not skip)", buffer);
         }
     }
+    compiled
+}
+
+// Post-processes the doc partials compiled this run, see 'highlight.rs'
+// Restored partials are stored before this, so they are highlighted too
+fn highlight_partials(
+    (config, _, shared_metadata): Shared,
+    compiled: &[usize],
+    timings: &mut Timings,
+) {
+    if !config.highlight {
+        return;
+    }
+    for i in compiled {
+        let doc_loc = shared_metadata[*i].doc_loc.as_str();
+        let mut doc = String::new();
+        read_file(Path::new(doc_loc), &mut doc).or_die(1);
+        let highlighted = timings.time(
+            Stage::Highlight,
+            || doc_loc.escape(),
+            || highlight::highlight_html(doc.as_str()),
+        );
+        if let Some(highlighted) = highlighted {
+            write_file(doc_loc, highlighted.as_str()).or_die(1);
+        }
+    }
 }
 
 /******************************************************************************/
@@ -354,12 +676,19 @@ fn htmlify_into_partials<'input_path, 'log>(
 struct LinkerViewMetadata<'input_path, 'lang_group_list, 'shared> {
     id: &'input_path str,
     frontmatter_serialised: String,
+    frontmatter_json: String, // Only filled when '--linker-stdin' is set
     series_cache_lines: Vec<String>,
     tags_cache_lines: Vec<String>,
     lang: &'lang_group_list str,
     relative_output_loc: String,
     title: &'shared str,
     other_langs: (&'lang_group_list str, &'lang_group_list str),
+    related: Vec<(String, String)>, // (relative url, title), see --related-count
+    word_count: usize,              // CJK characters count as one word each
+    reading_minutes: usize,
+    excerpt: String, // Single line, see 'EXCERPT_LENGTH'
+    is_related_outdated: bool,
+    is_fallback: bool, // See 'ViewMetadata'
 }
 
 fn linker_metadata_new<'input_path, 'lang_group_list, 'shared>(
@@ -372,8 +701,14 @@ fn linker_metadata_new<'input_path, 'lang_group_list, 'shared>(
     // So first render the links into 'view_links'
     let view_count = shared_metadata.len();
     let mut linker_metadata = Vec::with_capacity(view_count);
-    for (_, j, _, _, view_data) in walk(shared_metadata) {
+    let mut format_errors = Vec::new();
+    for (_, j, _, post_range, view_data) in walk(shared_metadata) {
         let path = &input_list[j];
+        let is_fallback = view_data.is_fallback;
+        let first_stub = shared_metadata[post_range]
+            .iter()
+            .find(|view| view.is_fallback)
+            .map(|view| view.lang.start);
         let frontmatter = Frontmatter::new(
             view_data.frontmatter_string.as_str(),
             path.created,
@@ -385,21 +720,273 @@ fn linker_metadata_new<'input_path, 'lang_group_list, 'shared>(
 
         linker_metadata.push_and_check(LinkerViewMetadata {
             frontmatter_serialised: frontmatter.serialise(),
-            tags_cache_lines: frontmatter.format_to_tag_cache(path.stem, lang),
-            series_cache_lines: frontmatter.format_to_series_cache(path.stem, lang),
+            frontmatter_json: {
+                let mut buffer = String::new();
+                if config.linker_stdin {
+                    frontmatter.serialise_json(&mut buffer);
+                }
+                buffer
+            },
+            // Stubs are not listed under the tags or series
+            tags_cache_lines: if is_fallback {
+                Vec::new()
+            } else {
+                frontmatter.format_to_tag_cache(path.stem, lang)
+            },
+            series_cache_lines: if is_fallback {
+                Vec::new()
+            } else {
+                frontmatter.format_to_series_cache(path.stem, lang)
+            },
             lang,
-            relative_output_loc: frontmatter.format(config.output_format, path.stem, lang),
+            // Stubs ignore 'permalink' as it is likely for the first view only
+            // Reported together after the loop
+            relative_output_loc: frontmatter
+                .format(
+                    match frontmatter.permalink() {
+                        Some(permalink) if !is_fallback => permalink,
+                        _ => output_format(config, lang),
+                    },
+                    path.stem,
+                    lang,
+                )
+                .unwrap_or_else(|err| {
+                    let source = path.path.to_string_lossy();
+                    format_errors.push([source.escape().as_str(), ": ", err.as_str()].join(""));
+                    String::new()
+                }),
             id: path.stem,
             title: match frontmatter.lookup("title") {
                 Some(Value::Utf8(s)) => s,
                 _ => "",
             },
-            other_langs: exclude(&lang_group_list[j], lang),
+            other_langs: other_view_langs(&lang_group_list[j], lang, is_fallback, first_stub),
+            related: Vec::new(),
+            is_related_outdated: false,
+            is_fallback,
+            word_count: 0,
+            reading_minutes: 0,
+            // Filled from the other sources in 'doc_metadata_new()'
+            excerpt: match frontmatter.lookup("summary") {
+                Some(Value::Utf8(s)) if !is_fallback => text::collapse_whitespace(s),
+                _ => String::new(),
+            },
         });
     }
+    format_errors.dedup(); // Views of a post often fail the same way
+    if !format_errors.is_empty() {
+        Err::<(), _>(
+            [
+                "Invalid output paths:\n- ",
+                format_errors.join("\n- ").as_str(),
+            ]
+            .join(""),
+        )
+        .or_die(1);
+    }
     linker_metadata
 }
 
+// In graphemes, only applies to excerpts from the first paragraph
+const EXCERPT_LENGTH: usize = 160;
+
+// Uses the compiled doc partial as markup syntax would inflate counts
+// Must be run after 'htmlify_into_partials()'
+fn doc_metadata_new((_, _, shared_metadata): Shared, linker_metadata: &mut [LinkerViewMetadata]) {
+    debug_assert_eq!(shared_metadata.len(), linker_metadata.len());
+    let mut doc = String::new();
+    for (shared, data) in shared_metadata.iter().zip(linker_metadata.iter_mut()) {
+        // Stubs have no content to count or excerpt
+        if shared.is_fallback {
+            continue;
+        }
+        doc.clear();
+        let doc_loc = Path::new(shared.doc_loc.as_str());
+        // 'relink' does not compile, so views may never have been compiled
+        if doc_loc.exists() {
+            read_file(doc_loc, &mut doc).or_die(1);
+        } else {
+            eprintln!(
+                "Warning: {} is missing, counting it as empty. Run `compile` to create it",
+                shared.doc_loc.escape()
+            );
+        }
+        let counts = text::count_words(text::strip_tags(doc.as_str()).as_str());
+        data.word_count = counts.0 + counts.1;
+        data.reading_minutes = text::reading_minutes(counts);
+
+        // Priority is 'summary' frontmatter, 'api_excerpt:', first paragraph
+        if data.excerpt.is_empty() {
+            data.excerpt = match (&shared.excerpt_marker, text::first_paragraph(&doc)) {
+                (Some(marker), _) => marker.clone(),
+                (None, Some(paragraph)) => {
+                    let plain = text::collapse_whitespace(&text::strip_tags(paragraph));
+                    text::truncate_graphemes(plain.as_str(), EXCERPT_LENGTH)
+                }
+                (None, None) => String::new(),
+            };
+        }
+    }
+}
+
+// Reads from the caches so must be run after 'write_caches()'
+// 'old_label_caches' is the (tags, series) caches before 'write_caches()' and
+// is used to find the views whose related posts changed due to tag changes
+fn related_posts_new(
+    (config, _, _): Shared,
+    linker_metadata: &mut [LinkerViewMetadata],
+    old_label_caches: Option<(&str, &str)>,
+) {
+    let count = parse_num_option::<usize>(config.related_count, "related-count").or_die(1);
+    let count = match count {
+        Some(count) if count > 0 => count,
+        _ => return,
+    };
+
+    let (mut tags_cache, mut series_cache, mut link_cache) =
+        (String::new(), String::new(), String::new());
+    read_file(Path::new(config.tags_cache.as_str()), &mut tags_cache).or_eprint(0);
+    read_file(Path::new(config.series_cache.as_str()), &mut series_cache).or_eprint(0);
+    read_file(Path::new(config.link_cache.as_str()), &mut link_cache).or_eprint(0);
+    let labels = related::parse_label_caches(tags_cache.as_str(), series_cache.as_str());
+
+    let mut urls = HashMap::with_capacity(link_cache.lines().count());
+    for (id, lang, loc) in link_cache.lines().filter_map(parse_link_cache_line) {
+        urls.insert((id, lang), loc);
+    }
+
+    for data in linker_metadata.iter_mut() {
+        data.related = related::rank(&labels, data.id, data.lang, count)
+            .into_iter()
+            .filter_map(|id| {
+                let url = urls.get(&(id, data.lang))?;
+                let title = labels.get(&(id, data.lang)).map(|e| e.title).unwrap_or("");
+                Some((url.to_string(), title.to_string()))
+            })
+            .collect();
+    }
+
+    // Views that had their tags/series changed affect the related posts of
+    // every view that shares (or used to share) one of those labels
+    if let Some((old_tags, old_series)) = old_label_caches {
+        let old_labels = related::parse_label_caches(old_tags, old_series);
+        let mut affected = HashMap::new();
+        for data in linker_metadata.iter() {
+            let key = (data.id, data.lang);
+            let old = old_labels
+                .get(&key)
+                .map(|e| e.labels.as_slice())
+                .unwrap_or(&[]);
+            let new = labels.get(&key).map(|e| e.labels.as_slice()).unwrap_or(&[]);
+            if old != new {
+                for label in old.iter().chain(new.iter()) {
+                    affected.insert((data.lang, *label), ());
+                }
+            }
+        }
+        for data in linker_metadata.iter_mut() {
+            data.is_related_outdated = labels
+                .get(&(data.id, data.lang))
+                .map(|e| {
+                    e.labels
+                        .iter()
+                        .any(|l| affected.contains_key(&(data.lang, *l)))
+                })
+                .unwrap_or(false);
+        }
+    }
+}
+
+// Views in the default language may drop the '{lang}' segment from the URL
+fn output_format<'config>(config: &RequiredConfigs<'config>, lang: &str) -> &'config str {
+    match (config.default_lang, config.default_output_format) {
+        (Some(default_lang), Some(format)) if default_lang == lang => format,
+        _ => config.output_format,
+    }
+}
+
+fn validate_fallback_stubs(config: &RequiredConfigs) -> Result<(), String> {
+    if config.fallback_stubs && config.site_langs.is_none() {
+        return Err("--fallback-stubs requires --site-langs to be set".to_string());
+    }
+    Ok(())
+}
+
+fn validate_output_formats(config: &RequiredConfigs) -> Result<(), String> {
+    Frontmatter::validate_format(config.output_format)?;
+    if let Some(format) = config.default_output_format {
+        Frontmatter::validate_format(format)?;
+    }
+    Ok(())
+}
+
+// Two views with the same output would silently overwrite each other
+// Also checks against the views of posts not being processed in the link cache
+fn check_output_collisions(
+    (config, input_list, _): Shared,
+    linker_metadata: &[LinkerViewMetadata],
+) -> Result<(), String> {
+    let mut id_map = HashMap::with_capacity(input_list.len());
+    for path in input_list {
+        id_map.insert(path.stem, ());
+    }
+
+    let mut old_cache = String::new();
+    let _ = read_file(Path::new(config.link_cache.as_str()), &mut old_cache);
+    let cached = old_cache
+        .lines()
+        .filter_map(parse_link_cache_line)
+        .filter(|(id, _, _)| !id_map.contains_key(id));
+    let current = linker_metadata
+        .iter()
+        .map(|data| (data.id, data.lang, data.relative_output_loc.as_str()));
+
+    let mut seen: HashMap<&str, (&str, &str)> = HashMap::with_capacity(linker_metadata.len());
+    let mut collisions = Vec::new();
+    for (id, lang, loc) in current.chain(cached) {
+        if let Some((other_id, other_lang)) = seen.insert(loc, (id, lang)) {
+            collisions.push(format!(
+                "{} is the output of both {} ({}) and {} ({})",
+                loc.escape(),
+                other_id.escape(),
+                other_lang,
+                id.escape(),
+                lang,
+            ));
+        }
+    }
+
+    if collisions.is_empty() {
+        Ok(())
+    } else {
+        Err([
+            "Output path collisions (is '{lang}' missing from the output format?):\n- ",
+            collisions.join("\n- ").as_str(),
+        ]
+        .join(""))
+    }
+}
+
+// @FORMAT link cache
+// id,lang,path,word_count,reading_minutes
+// Returns (id, lang, path). Caches from before the word count and reading
+// time were added have only the first three columns
+fn parse_link_cache_line(line: &str) -> Option<(&str, &str, &str)> {
+    let mut iter = line.splitn(3, ',');
+    let (id, lang, rest) = (iter.next()?, iter.next()?, iter.next()?);
+    // The path could have commas, so the two numbers are split from the end
+    let mut numbers = rest.rsplitn(3, ',');
+    let path = match (numbers.next(), numbers.next(), numbers.next()) {
+        (Some(minutes), Some(count), Some(path)) if is_number(minutes) && is_number(count) => path,
+        _ => rest,
+    };
+    Some((id, lang, path))
+}
+
+fn is_number(text: &str) -> bool {
+    !text.is_empty() && text.bytes().all(|b| b.is_ascii_digit())
+}
+
 fn output_target(config: &RequiredConfigs, linker_view_metadata: &LinkerViewMetadata) -> String {
     [config.public_dir, "/", linker_view_metadata.relative_output_loc.as_str()].join("")
 }
@@ -408,9 +995,11 @@ fn join_partials(
     (config, input_list, shared_metadata): Shared,
     changelog: &UpdateTimes,
     linker_metadata: &[LinkerViewMetadata],
+    timings: &mut Timings,
 ) {
     //println!("{:#?}", linker_metadata);
     //std::process::exit(0);
+    let limits = limits(config, config.linker_timeout, "linker-timeout");
 
     // Run the linker to join the partials (toc and doc)
     for (i, j, _, post_range, shared) in walk(shared_metadata) {
@@ -432,15 +1021,38 @@ fn join_partials(
         //    );
         //if true {
         //} else
-        if config.force || shared.is_outdated || is_target_missing_or_outdated {
-            let args = fmt_linker_args(
-                config,
-                target.as_str(),
-                &shared_metadata[i],
-                post_data,
-                my_data,
-            );
+        if config.force
+            || shared.is_outdated
+            || is_target_missing_or_outdated
+            || my_data.is_related_outdated
+        {
+            create_parent_dir(target.as_str()).or_die(1);
+            // @TODO Only link if out of date or final file is missing
+            eprintln!("Linking {} {}", my_data.lang, target.escape());
 
+            let prefix = stderr_prefix(my_data.id, my_data.lang);
+            let stderr_to = Stderr::Shown(&prefix);
+            if config.linker_stdin {
+                let json = fmt_linker_json(config, target.as_str(), shared, post_data, my_data);
+                let stdout = timings.time(
+                    Stage::Link,
+                    || label(my_data.id, my_data.lang, config.linker),
+                    || {
+                        let stdin = [json.as_str()];
+                        let linker = Path::new(config.linker);
+                        command_run(linker, Some(&stdin), &[], &[], stderr_to, None, limits)
+                    },
+                );
+                let stdout = stdout.map_err(|err| while_processing(my_data.id, my_data.lang, err));
+                print!("{}", stdout.or_die(1));
+
+                if config.explicit {
+                    eprint!("=== STDIN ===\n{}\n", json);
+                }
+                continue;
+            }
+
+            let args = fmt_linker_args(config, target.as_str(), shared, post_data, my_data);
             let args = {
                 let mut borrow: Vec<&str> = Vec::with_capacity(args.len());
                 for entry in &args {
@@ -449,13 +1061,16 @@ fn join_partials(
                 borrow
             };
 
-            create_parent_dir(target.as_str()).or_die(1);
-            // @TODO Only link if out of date or final file is missing
-            eprintln!("Linking {} {}", my_data.lang, target.escape());
-            print!(
-                "{}",
-                command_run(Path::new(config.linker), None, &args).or_die(1)
+            let stdout = timings.time(
+                Stage::Link,
+                || label(my_data.id, my_data.lang, config.linker),
+                || {
+                    let linker = Path::new(config.linker);
+                    command_run(linker, None, &args, &[], stderr_to, None, limits)
+                },
             );
+            let stdout = stdout.map_err(|err| while_processing(my_data.id, my_data.lang, err));
+            print!("{}", stdout.or_die(1));
 
             if config.explicit {
                 eprint!("=== Arg 1: Frontmatter ====\n{}", &args[0]);
@@ -479,6 +1094,7 @@ fn write_caches(
     changelog: &UpdateTimes,
     linker_metadata: &[LinkerViewMetadata],
     is_update: bool,
+    timings: &mut Timings,
 ) {
     debug_assert_eq!(changelog.0.len(), input_list.len());
     debug_assert_eq!(shared_metadata.len(), linker_metadata.len());
@@ -520,14 +1136,20 @@ fn write_caches(
             $msg:literal
         ) => {
             let loc = $loc;
-            let mut old = String::new();
-            let (capacity, mut cache) =
-                read_old_and_sieve(&$id_map, loc, &mut old, $to_add, $id_index);
-            if $is_update {
-                cache.extend($insert);
-            }
-            eprintln!($msg, loc.escape());
-            write_after_add(capacity, cache, loc);
+            timings.time(
+                Stage::CacheWrite,
+                || loc.to_string(),
+                || {
+                    let mut old = String::new();
+                    let (capacity, mut cache) =
+                        read_old_and_sieve(&$id_map, loc, &mut old, $to_add, $id_index);
+                    if $is_update {
+                        cache.extend($insert);
+                    }
+                    eprintln!($msg, loc.escape());
+                    write_after_add(capacity, cache, loc);
+                },
+            );
         };
     }
     fn write_after_add(capacity: usize, mut cache: Vec<Cow<str>>, loc: &str) {
@@ -546,7 +1168,13 @@ fn write_caches(
 
     if config.force || has_any_change {
         eprintln!("Saving file update times to {}", config.changelog.escape());
-        changelog.write_to(config.changelog.as_str()).or_die(1);
+        timings
+            .time(
+                Stage::CacheWrite,
+                || config.changelog.clone(),
+                || changelog.write_to(config.changelog.as_str()),
+            )
+            .or_die(1);
 
         // @FORMAT
         // Specifically separating path and path so csv can support
@@ -575,7 +1203,8 @@ fn write_caches(
         }
 
         // @FORMAT link cache
-        // Last column is path (only column that could have commas as data)
+        // Path is the third column so older linkers reading 'id,lang,path'
+        // still work, the word count and reading time are appended after it
         update_cache! {
             @id_list_to_add    id_map,
             @location          config.link_cache.as_str(),
@@ -586,8 +1215,13 @@ fn write_caches(
                 linker_metadata
                     .iter()
                     // @FORMAT
-                    .map(|d| [d.id, d.lang, d.relative_output_loc.as_str()])
-                    .map(|array| array.join(","))
+                    .map(|d| [
+                        d.id,
+                        d.lang,
+                        d.relative_output_loc.as_str(),
+                        d.word_count.to_string().as_str(),
+                        d.reading_minutes.to_string().as_str(),
+                    ].join(","))
                     .map(Cow::Owned);
 
             "Saving link cache to {}"
@@ -614,10 +1248,132 @@ fn write_caches(
             "Saving series cache to {}"
         }
 
+        // @FORMAT excerpt cache
+        // Last column is the excerpt (only column that could have commas)
+        update_cache! {
+            @id_list_to_add    id_map,
+            @location          config.excerpt_cache.as_str(),
+            @to_add_line_count view_count,
+            @id_index_in_cache 0,
+
+            if is_update then
+                linker_metadata
+                    .iter()
+                    // @FORMAT
+                    .map(|d| [d.id, d.lang, d.excerpt.as_str()].join(","))
+                    .map(Cow::Owned);
+
+            "Saving excerpt cache to {}"
+        }
+
     //eprintln!("{:#?}\n", link);
     } else {
         eprintln!("No change in posts detected, caches unmodified (use --force to override)");
     }
+
+    // @FORMAT search cache
+    // Last column is the JSON entry (only column that could have commas)
+    if config.search_index {
+        // Only re-tokenise changed posts, keeping the old lines for others
+        // Posts not yet in the cache (e.g. just after enabling
+        // --search-index) are added so the index fills in without --force
+        let mut old_search = String::new();
+        let _ = read_file(Path::new(config.search_cache.as_str()), &mut old_search);
+        let mut cached = HashMap::with_capacity(old_search.lines().count());
+        for line in old_search.lines() {
+            cached.insert(line.split(',').next().unwrap(), ());
+        }
+        let mut search_id_map = HashMap::with_capacity(input_list.len());
+        for (view_data, data) in zip!(shared_metadata, linker_metadata) {
+            let is_missing = !cached.contains_key(data.id);
+            if !is_update || config.force || view_data.is_outdated || is_missing {
+                search_id_map.insert(data.id, ());
+            }
+        }
+        // So unlike the other caches, this may be written with no post changed
+        if !(config.force || has_any_change || !search_id_map.is_empty()) {
+            return;
+        }
+        update_cache! {
+            @id_list_to_add    search_id_map,
+            @location          config.search_cache.as_str(),
+            @to_add_line_count view_count,
+            @id_index_in_cache 0,
+
+            if is_update then
+                zip!(shared_metadata, linker_metadata)
+                    .filter(|(view_data, _)| !view_data.is_fallback) // noindex
+                    .filter(|(_, data)| search_id_map.contains_key(data.id))
+                    .map(|(view_data, data)| search_cache_line(view_data, data))
+                    .map(Cow::Owned);
+
+            "Saving search cache to {}"
+        }
+        write_search_indices(config, linker_metadata);
+    }
+}
+
+// @FORMAT search cache
+// id,lang,{"id","lang","title","url","tags","tokens"}
+fn search_cache_line(view_data: &ViewMetadata, data: &LinkerViewMetadata) -> String {
+    let mut doc = String::new();
+    read_file(Path::new(view_data.doc_loc.as_str()), &mut doc).or_die(1);
+    let tokens = text::tokenise(text::strip_tags(doc.as_str()).as_str());
+
+    let mut buffer = [data.id, ",", data.lang, ",{\"id\":"].join("");
+    push_json_str(&mut buffer, data.id);
+    buffer.push_str(",\"lang\":");
+    push_json_str(&mut buffer, data.lang);
+    buffer.push_str(",\"title\":");
+    push_json_str(&mut buffer, data.title);
+    buffer.push_str(",\"url\":");
+    push_json_str(&mut buffer, data.relative_output_loc.as_str());
+    buffer.push_str(",\"tags\":");
+    // @FORMAT tags cache, label is the first column
+    let tags = data.tags_cache_lines.iter();
+    push_json_list(&mut buffer, tags.filter_map(|line| line.split(',').next()));
+    buffer.push_str(",\"tokens\":");
+    push_json_list(&mut buffer, tokens.iter().map(String::as_str));
+    buffer.push('}');
+    buffer
+}
+
+// Regenerates every index from the search cache as they are just a join
+// Must be run after the search cache is written
+fn write_search_indices(config: &RequiredConfigs, linker_metadata: &[LinkerViewMetadata]) {
+    let mut cache = String::new();
+    read_file(Path::new(config.search_cache.as_str()), &mut cache).or_die(1);
+
+    // Include the langs of this batch so deleting the last view empties it
+    let mut indices: HashMap<&str, Vec<&str>> = HashMap::new();
+    for data in linker_metadata {
+        indices.entry(data.lang).or_default();
+    }
+    for line in cache.lines() {
+        let mut iter = line.splitn(3, ',');
+        if let (Some(_), Some(lang), Some(entry)) = (iter.next(), iter.next(), iter.next()) {
+            indices.entry(lang).or_default().push(entry);
+        }
+    }
+
+    for (lang, entries) in indices {
+        let loc = [
+            config.public_dir,
+            "/",
+            config.blog_relative,
+            "/search/",
+            if lang.is_empty() { "index" } else { lang },
+            ".json",
+        ]
+        .join("");
+        eprintln!("Saving search index to {}", loc.escape());
+        create_parent_dir(loc.as_str()).or_die(1);
+        write_file(
+            loc.as_str(),
+            ["[", entries.join(",").as_str(), "]"].join("").as_str(),
+        )
+        .or_die(1);
+    }
 }
 
 macro_rules! build_and_count_capacity {
@@ -631,63 +1387,159 @@ macro_rules! build_and_count_capacity {
     };
     (@count) => { 0 };
     (@count $entry:expr, $($tt:tt)*) => {
-        1 + build_and_count_capacity!(@count $($tt)*);
+        1 + build_and_count_capacity!(@count $($tt)*)
     };
 }
 
-// 'link_view_sections()' but for a single view
-// Returns the output of the command (probably just ignore Ok() case)
+// Metadata shared by both the argv protocol and the '--linker-stdin' protocol
 // Mostly separate this for the white space
-fn fmt_linker_args<'shared, 'frontmatter_string>(
-    config: &RequiredConfigs,
-    local_target: &str,
-    shared: &'shared ViewMetadata,
-    post_data: &[LinkerViewMetadata],
-    data: &'frontmatter_string LinkerViewMetadata<'shared, '_, 'frontmatter_string>,
-) -> Vec<Cow<'frontmatter_string, str>> {
-    let relative_target = data.relative_output_loc.as_str();
-    let lang_count = shared.post_lang_count;
-    // ALL label is lang_count of 0, we want: min(0, lang_count - 1)
-    let other_lang_count = if lang_count > 0 { lang_count - 1 } else { 0 };
-
-    // File metadata
-
+fn linker_keyvals<'a>(
+    config: &'a RequiredConfigs,
+    local_target: &'a str,
+    shared: &'a ViewMetadata,
+    data: &'a LinkerViewMetadata,
+) -> Vec<(&'static str, Cow<'a, str>)> {
     // Counts the capacity for me and pushes
     build_and_count_capacity! {
-        let mut api_keyvals, capacity = other_lang_count,
+        let mut keyvals, capacity = 0,
         +
-        // User data (specified within post) pushed as first arg
-        Cow::Borrowed(data.frontmatter_serialised.as_str()),
-
-        // Remaining args are the api-calculated metadata
-        Cow::Owned(["domain:", config.domain].join("")),
-        Cow::Owned(["blog_relative:", config.blog_relative].join("")),
-        Cow::Owned(["link_cache:", config.link_cache.as_str()].join("")),
-        Cow::Owned(["series_cache:", config.series_cache.as_str()].join("")),
-        Cow::Owned(["language:", data.lang].join("")),
-        Cow::Owned(["local_templates_dir:", config.templates_dir].join("")),
-        Cow::Owned(["local_toc_path:", shared.toc_loc.as_str()].join("")),
-        Cow::Owned(["local_doc_path:", shared.doc_loc.as_str()].join("")),
-        Cow::Owned(["local_output_path:", local_target].join("")),
-        Cow::Owned(["relative_output_url:", relative_target].join("")),
-        Cow::Owned([
-            "other_view_langs:",
-            data.other_langs.0,
-            data.other_langs.1
-        ].join("")),
+        ("domain", Cow::Borrowed(config.domain)),
+        ("blog_relative", Cow::Borrowed(config.blog_relative)),
+        ("link_cache", Cow::Borrowed(config.link_cache.as_str())),
+        ("series_cache", Cow::Borrowed(config.series_cache.as_str())),
+        ("tags_cache", Cow::Borrowed(config.tags_cache.as_str())),
+        ("language", Cow::Borrowed(data.lang)),
+        ("local_templates_dir", Cow::Borrowed(config.templates_dir)),
+        ("local_toc_path", Cow::Borrowed(shared.toc_loc.as_str())),
+        ("local_doc_path", Cow::Borrowed(shared.doc_loc.as_str())),
+        ("local_output_path", Cow::Borrowed(local_target)),
+        ("relative_output_url", Cow::Borrowed(data.relative_output_loc.as_str())),
+        ("word_count", Cow::Owned(data.word_count.to_string())),
+        ("reading_minutes", Cow::Owned(data.reading_minutes.to_string())),
+        ("excerpt_cache", Cow::Borrowed(config.excerpt_cache.as_str())),
+        ("excerpt", Cow::Borrowed(data.excerpt.as_str())),
+        // The stub's language if a stub (should be noindex), otherwise empty
+        ("fallback_for", Cow::Borrowed(if shared.is_fallback { data.lang } else { "" })),
     }
+    debug_assert_eq!(capacity, keyvals.len());
+    keyvals
+}
+
+// The views of the same post other than 'shared', i.e. (lang, relative url)
+fn sibling_views<'a>(
+    shared: &'a ViewMetadata,
+    post_data: &'a [LinkerViewMetadata],
+) -> impl Iterator<Item = (&'a str, &'a str)> {
     post_data
         .iter()
         .enumerate()
-        .filter(|(i, _)| i != &shared.view_index)
+        .filter(move |(i, _)| i != &shared.view_index)
+        .filter(move |(_, data)| !(shared.is_fallback && data.is_fallback))
         .map(|(_, data)| (data.lang, data.relative_output_loc.as_str()))
+}
+
+// 'link_view_sections()' but for a single view
+// Returns the output of the command (probably just ignore Ok() case)
+fn fmt_linker_args<'a>(
+    config: &'a RequiredConfigs,
+    local_target: &'a str,
+    shared: &'a ViewMetadata,
+    post_data: &'a [LinkerViewMetadata],
+    data: &'a LinkerViewMetadata,
+) -> Vec<Cow<'a, str>> {
+    // Stubs skip the other stubs, so count rather than use 'post_lang_count'
+    let other_lang_count = sibling_views(shared, post_data).count();
+    let keyvals = linker_keyvals(config, local_target, shared, data);
+
+    // + 2 for frontmatter and 'other_view_langs'
+    let capacity = keyvals.len() + other_lang_count + data.related.len() + 2;
+    let mut api_keyvals = Vec::with_capacity(capacity);
+
+    // User data (specified within post) pushed as first arg
+    api_keyvals.push_and_check(Cow::Borrowed(data.frontmatter_serialised.as_str()));
+
+    // Remaining args are the api-calculated metadata
+    for (key, value) in keyvals {
+        api_keyvals.push_and_check(Cow::Owned([key, ":", value.as_ref()].join("")));
+    }
+    api_keyvals.push_and_check(Cow::Owned(
+        ["other_view_langs:", data.other_langs.0, data.other_langs.1].join(""),
+    ));
+    sibling_views(shared, post_data)
         .map(|(lang, loc)| ["relative_", lang, "_view:", loc].join(""))
         .for_each(|keyval| api_keyvals.push_and_check(Cow::Owned(keyval)));
+
+    // Ordered from most to least related
+    // The url cannot contain commas (see 'Frontmatter::validate_format()')
+    for (i, (url, title)) in data.related.iter().enumerate() {
+        let n = (i + 1).to_string();
+        api_keyvals.push_and_check(Cow::Owned(["related_", &n, ":", url, ",", title].join("")));
+    }
     assert_eq!(capacity, api_keyvals.len());
 
     api_keyvals
 }
 
+// The '--linker-stdin' alternative to 'fmt_linker_args()'
+// Same information, but as a single JSON object so that values can span
+// multiple lines, contain colons, and not run into argv size limits
+//
+// {
+//   "frontmatter": { "title": "...", "tags": ["..."], ... },
+//   "domain": "...", ... (same keys as the argv protocol)
+//   "other_view_langs": ["en", "jp"],
+//   "other_views": { "en": "<relative_url>", "jp": "<relative_url>" },
+//   "related": [{ "url": "<relative_url>", "title": "..." }, ...]
+// }
+fn fmt_linker_json(
+    config: &RequiredConfigs,
+    local_target: &str,
+    shared: &ViewMetadata,
+    post_data: &[LinkerViewMetadata],
+    data: &LinkerViewMetadata,
+) -> String {
+    let mut buffer = String::new();
+    buffer.push_str("{\"frontmatter\":");
+    buffer.push_str(data.frontmatter_json.as_str());
+    for (key, value) in linker_keyvals(config, local_target, shared, data) {
+        buffer.push(',');
+        push_json_str(&mut buffer, key);
+        buffer.push(':');
+        push_json_str(&mut buffer, value.as_ref());
+    }
+
+    buffer.push_str(",\"other_view_langs\":");
+    let other_langs = data.other_langs.0.split_whitespace();
+    push_json_list(
+        &mut buffer,
+        other_langs.chain(data.other_langs.1.split_whitespace()),
+    );
+
+    buffer.push_str(",\"other_views\":{");
+    for (i, (lang, loc)) in sibling_views(shared, post_data).enumerate() {
+        if i > 0 {
+            buffer.push(',');
+        }
+        push_json_str(&mut buffer, lang);
+        buffer.push(':');
+        push_json_str(&mut buffer, loc);
+    }
+
+    buffer.push_str("},\"related\":[");
+    for (i, (url, title)) in data.related.iter().enumerate() {
+        if i > 0 {
+            buffer.push(',');
+        }
+        buffer.push_str("{\"url\":");
+        push_json_str(&mut buffer, url);
+        buffer.push_str(",\"title\":");
+        push_json_str(&mut buffer, title);
+        buffer.push('}');
+    }
+    buffer.push_str("]}\n");
+    buffer
+}
+
 /******************************************************************************
  * Helper functions
  ******************************************************************************/
@@ -727,6 +1579,14 @@ impl<'log> UpdateTimes<'log> {
             .unwrap_or(true)
     }
 
+    // e.g. for when a file that 'id' includes was modified at 'updated'
+    fn check_if_outdated_since(&self, id: &PathReadMetadata, updated: DateTime<Utc>) -> bool {
+        self.0
+            .get(id.stem)
+            .map(|log| &updated > log)
+            .unwrap_or(true)
+    }
+
     fn update(&mut self, id: &PathReadMetadata<'log>) {
         self.0.insert(id.stem, Utc::now());
     }
@@ -757,6 +1617,21 @@ impl<'log> UpdateTimes<'log> {
     }
 }
 
+// 'lang_list' is the languages of the post's views then of its stubs, where
+// 'first_stub' is the start of the stubs. Stubs only list the real views so
+// that language switchers on a stub do not lead to yet more stubs
+fn other_view_langs<'a>(
+    lang_list: &'a str,
+    lang: &'a str,
+    is_fallback: bool,
+    first_stub: Option<usize>,
+) -> (&'a str, &'a str) {
+    match first_stub {
+        Some(start) if is_fallback => (lang_list[..start].trim_end(), ""),
+        _ => exclude(lang_list, lang),
+    }
+}
+
 // Check tests for use case
 // Remove an entry of a `vec.join(" ")` preserving the correct space delimiters
 fn exclude<'a>(space_delimited_str: &'a str, to_skip: &'a str) -> (&'a str, &'a str) {
@@ -822,7 +1697,10 @@ fn delete_file(loc: &str) -> Result<(), String> {
  ******************************************************************************/
 #[cfg(test)]
 mod tests {
-    use super::exclude;
+    use super::{
+        doc_metadata_new, exclude, fmt_linker_json, other_view_langs, parse_link_cache_line,
+        LinkerViewMetadata, RequiredConfigs, ViewMetadata,
+    };
     fn merge(tuple: (&str, &str)) -> String {
         let mut merged = String::with_capacity(tuple.0.len() + tuple.1.len());
         merged.push_str(tuple.0);
@@ -841,4 +1719,139 @@ mod tests {
         assert_eq!(merge(exclude("en jp zh", "jp")), "en zh");
         assert_eq!(merge(exclude("en jp zh", "zh")), "en jp");
     }
+
+    #[test]
+    fn other_view_langs_test() {
+        // 'jp' and 'zh' are stubs
+        let other =
+            |lang, is_fallback| merge(other_view_langs("en jp zh", lang, is_fallback, Some(3)));
+        assert_eq!(other("en", false), "jp zh");
+        assert_eq!(other("jp", true), "en");
+        assert_eq!(other("zh", true), "en");
+        assert_eq!(merge(other_view_langs("en jp", "en", false, None)), "jp");
+    }
+
+    #[test]
+    fn link_cache_test() {
+        let parse = parse_link_cache_line;
+        assert_eq!(
+            parse("a,en,a/en.html,420,2"),
+            Some(("a", "en", "a/en.html"))
+        );
+        assert_eq!(parse("a,en,a,b.html,420,2"), Some(("a", "en", "a,b.html")));
+        // Written before word_count,reading_minutes were added
+        assert_eq!(parse("a,en,a/en.html"), Some(("a", "en", "a/en.html")));
+        assert_eq!(parse("a,en,a,b.html"), Some(("a", "en", "a,b.html")));
+        assert_eq!(parse("a,en"), None);
+    }
+
+    fn test_config() -> crate::Config {
+        let mut config = crate::Config::new();
+        config.api_dir = Some("api".to_string());
+        config.blog_relative = Some("blog".to_string());
+        config.cache_dir = Some("cache".to_string());
+        config.domain = Some("http://x".to_string());
+        config.linker = Some("linker".to_string());
+        config.output_format = Some("blog/{lang}/{file_stem}.html".to_string());
+        config.public_dir = Some("public".to_string());
+        config.templates_dir = Some("templates".to_string());
+        config
+    }
+
+    fn shared_view(doc_loc: &str, is_fallback: bool) -> ViewMetadata {
+        ViewMetadata {
+            view_index: 0,
+            is_outdated: false,
+            frontmatter_string: String::new(),
+            lang: 0..2,
+            post_lang_count: 2,
+            toc_loc: "cache/toc/en/a.html".to_string(),
+            doc_loc: doc_loc.to_string(),
+            excerpt_marker: None,
+            is_fallback,
+        }
+    }
+
+    fn linker_view<'a>(lang: &'a str, other_langs: &'a str) -> LinkerViewMetadata<'a, 'a, 'a> {
+        LinkerViewMetadata {
+            id: "a",
+            frontmatter_serialised: String::new(),
+            frontmatter_json: r#"{"title":"Say \"hi\""}"#.to_string(),
+            series_cache_lines: Vec::new(),
+            tags_cache_lines: Vec::new(),
+            lang,
+            relative_output_loc: ["blog/", lang, "/a.html"].join(""),
+            title: "Say \"hi\"",
+            other_langs: (other_langs, ""),
+            related: vec![("blog/b.html".to_string(), r"B\C".to_string())],
+            word_count: 3,
+            reading_minutes: 1,
+            excerpt: "a\tb".to_string(),
+            is_related_outdated: false,
+            is_fallback: false,
+        }
+    }
+
+    #[test]
+    fn linker_json_test() {
+        let config = test_config();
+        let config = RequiredConfigs::unwrap(&config);
+
+        let shared = shared_view("cache/doc/en/a.html", false);
+        let post_data = [linker_view("en", "jp"), linker_view("jp", "en")];
+        let json = fmt_linker_json(
+            &config,
+            "public/blog/en/a.html",
+            &shared,
+            &post_data,
+            &post_data[0],
+        );
+        assert_eq!(
+            json,
+            concat!(
+                r#"{"frontmatter":{"title":"Say \"hi\""},"domain":"http://x","#,
+                r#""blog_relative":"blog","link_cache":"cache/link.csv","#,
+                r#""series_cache":"cache/series.csv","tags_cache":"cache/tags.csv","#,
+                r#""language":"en","local_templates_dir":"templates","#,
+                r#""local_toc_path":"cache/toc/en/a.html","local_doc_path":"cache/doc/en/a.html","#,
+                r#""local_output_path":"public/blog/en/a.html","#,
+                r#""relative_output_url":"blog/en/a.html","word_count":"3","reading_minutes":"1","#,
+                r#""excerpt_cache":"cache/excerpt.csv","excerpt":"a\tb","fallback_for":"","#,
+                r#""other_view_langs":["jp"],"other_views":{"jp":"blog/jp/a.html"},"#,
+                r#""related":[{"url":"blog/b.html","title":"B\\C"}]}"#,
+                "\n",
+            )
+        );
+    }
+
+    #[test]
+    fn doc_metadata_test() {
+        let dir = crate::helpers::TempDir::new("doc-metadata");
+        let doc_loc = dir.0.join("a.html").to_string_lossy().to_string();
+        std::fs::write(&doc_loc, "<p>One two three.</p>").unwrap();
+        let missing = dir.0.join("missing.html").to_string_lossy().to_string();
+
+        let config = test_config();
+        let config = RequiredConfigs::unwrap(&config);
+        let shared = [
+            shared_view(&doc_loc, false),
+            shared_view(&missing, false),
+            shared_view(&missing, true),
+        ];
+        let mut linker_data = [
+            linker_view("en", ""),
+            linker_view("jp", ""),
+            linker_view("zh", ""),
+        ];
+        for data in linker_data.iter_mut() {
+            data.word_count = 0;
+            data.excerpt.clear();
+        }
+        // Neither the missing partial nor the stub (never read) are fatal
+        doc_metadata_new((&config, &[], &shared), &mut linker_data);
+        assert_eq!(linker_data[0].word_count, 3);
+        assert_eq!(linker_data[0].excerpt, "One two three.");
+        assert_eq!(linker_data[1].word_count, 0);
+        assert_eq!(linker_data[2].word_count, 0);
+    }
 }
@@ -49,6 +49,16 @@ impl<'a> ParseError<'a> {
         }
     }
 
+    // For when the text parsed was spliced from several files
+    pub fn with_source_row<'b>(
+        mut self,
+        locate: impl FnOnce(usize) -> (Cow<'b, str>, usize),
+    ) -> FullParseError<'a, 'b> {
+        let (filename, row) = locate(self.row);
+        self.row = row;
+        self.with_filename(filename)
+    }
+
     //#[inline]
     //pub fn warn(row: usize, context: &'a str, message: Cow<'a, str>) {
     //    //Self {
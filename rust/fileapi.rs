@@ -1,26 +1,244 @@
+#[cfg(feature = "markdown")]
+use crate::markdown;
+use crate::include::Origins;
+use crate::post::PostView;
 use crate::traits::ShellEscape;
-use std::io::Write;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
 use std::path::{Path, PathBuf};
-use std::process::{Command, Stdio};
+use std::process::{Child, ChildStdin, ChildStdout, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Capabilities
+// `<handler> capabilities` optionally lists (whitespace separated) what the
+// handler supports after 'CAPABILITIES_MARKER' on the first line, e.g.
+// 'polygot-capabilities: comment compile'. Handlers without it (it errors or
+// prints something else, such as usage text) get the defaults.
+// - 'comment', 'frontmatter', 'compile': the subcommands
+//   'frontmatter' is optional, handlers without it have empty frontmatter
+// - 'persistent': see below
+const CAPABILITIES_ARG: &str = "capabilities";
+const CAPABILITIES_MARKER: &str = "polygot-capabilities:";
+const DEFAULT_CAPABILITIES: [&str; 3] = ["comment", "frontmatter", "compile"];
+
+// Persistent mode
+// Spawning the handler for every call is slow for handlers with a heavy
+// startup (e.g. Ruby for asciidoctor). If the handler has the 'persistent'
+// capability, it is started once as `<handler> serve` and sent requests on its
+// STDIN until STDIN is closed. A frame is the byte length (ASCII decimal), a
+// newline, then that many bytes. The args are the same as in one-shot mode and
+// the environment is the 'Context' variables as 'KEY=value'.
+//   request:  <arg count>\n <frame per arg>
+//             <env count>\n <frame per variable> <frame of STDIN>
+//   response: <frame of exit status> <frame of STDOUT> <frame of STDERR>
+// The handler must read the full request before it responds.
+const SERVE_ARG: &str = "serve";
+
+// Limits
+// A hung or runaway process is killed along with its process group (so that
+// e.g. the ruby started by an asciidoctor wrapper script also dies). Without
+// any limits, processes run in our process group so that Ctrl-C reaches them.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Limits {
+    pub timeout: Option<Duration>,
+    pub max_output: Option<usize>, // Bytes per STDOUT and per STDERR
+}
+
+impl Limits {
+    fn is_limited(&self) -> bool {
+        self.timeout.is_some() || self.max_output.is_some()
+    }
+}
+
+const POLL_MIN: Duration = Duration::from_millis(1);
+const POLL_MAX: Duration = Duration::from_millis(50);
+const EXIT_GRACE: Duration = Duration::from_secs(5); // Without a timeout
+
+// Per-call context sent to 'frontmatter' and 'compile' as environment
+// variables, e.g. for localising admonition labels
+#[derive(Debug, Default)]
+pub struct Context<'a> {
+    pub lang: &'a str,
+    pub post_id: &'a str,
+    pub source_path: &'a str,
+    pub other_langs: &'a str, // Space separated
+    pub cache_dir: &'a str,
+    pub lines: Option<(&'a PostView<'a>, &'a Origins)>, // For 'locate()'
+}
+
+impl<'a> Context<'a> {
+    pub fn vars(&self) -> [(&'static str, &'a str); 5] {
+        [
+            ("POLYGOT_LANG", self.lang),
+            ("POLYGOT_POST_ID", self.post_id),
+            ("POLYGOT_SOURCE_PATH", self.source_path),
+            ("POLYGOT_OTHER_LANGS", self.other_langs),
+            ("POLYGOT_CACHE_DIR", self.cache_dir),
+        ]
+    }
+
+    // The file and row that 'view_row' of the STDIN came from
+    pub fn locate(&self, view_row: usize) -> Option<(Cow<'a, str>, usize)> {
+        let (view, origins) = self.lines?;
+        let row = view.source_row(view_row)?;
+        let (path, row) = origins.locate(row, Path::new(self.source_path));
+        Some((path.to_string_lossy(), row))
+    }
+}
+
+// Handler and linker STDERR is shown live, each line with this prefix
+pub fn stderr_prefix(post_id: &str, lang: &str) -> String {
+    if lang.is_empty() {
+        ["[", post_id, "] "].join("")
+    } else {
+        ["[", post_id, " ", lang, "] "].join("")
+    }
+}
+
+// Where the STDERR of a handler or linker goes
+#[derive(Clone, Copy)]
+pub enum Stderr<'a> {
+    Shown(&'a str),              // With 'stderr_prefix()'
+    Captured(&'a Mutex<String>), // For 'check-api' to show with the failure
+}
+
+// Handler map (with '--handler-map <file>')
+// Lines of '<extension> <handler> [args...]', where the handler is a file in
+// the api dir and the args (whitespace separated, no quoting) are appended to
+// every call. Extensions not in the map use the handler of the same name.
+//   # e.g. one script for both spellings, and pandoc for reStructuredText
+//   markdown  md
+//   rst       pandoc-wrapper --from rst
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Handler {
+    pub name: String,
+    pub args: Vec<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct HandlerMap(HashMap<String, Handler>);
+
+impl HandlerMap {
+    pub fn read(location: Option<&str>) -> Result<Self, String> {
+        match location {
+            Some(location) => {
+                let text = std::fs::read_to_string(location).map_err(|err| {
+                    [
+                        "Cannot read ",
+                        location.escape().as_str(),
+                        ". ",
+                        err.to_string().as_str(),
+                    ]
+                    .join("")
+                })?;
+                Self::parse(text.as_str()).map_err(|err| {
+                    [
+                        "In the handler map ",
+                        location.escape().as_str(),
+                        err.as_str(),
+                    ]
+                    .join("")
+                })
+            }
+            None => Ok(Self::default()),
+        }
+    }
+
+    fn parse(text: &str) -> Result<Self, String> {
+        let mut map = HashMap::new();
+        for (i, line) in text.lines().enumerate() {
+            let mut words = line.split_whitespace();
+            let extension = match words.next() {
+                Some(word) if !word.starts_with('#') => word.trim_start_matches('.'),
+                _ => continue,
+            };
+            let name = words.next().ok_or_else(|| {
+                format!(" on line {}, {} has no handler", i + 1, extension.escape())
+            })?;
+            let handler = Handler {
+                name: name.to_string(),
+                args: words.map(str::to_string).collect(),
+            };
+            if map.insert(extension.to_string(), handler).is_some() {
+                return Err(format!(
+                    " on line {}, {} is mapped more than once",
+                    i + 1,
+                    extension.escape()
+                ));
+            }
+        }
+        Ok(Self(map))
+    }
+
+    pub fn resolve(&self, extension: &str) -> Handler {
+        self.0.get(extension).cloned().unwrap_or_else(|| Handler {
+            name: extension.to_string(),
+            args: Vec::new(),
+        })
+    }
+}
+
+// Used as the handler path for labels/errors when using the built-in handler
+const BUILTIN_MARKDOWN: &str = "(built-in markdown)";
 
 #[derive(Debug)]
 pub struct FileApi {
     pathbuf: PathBuf,
+    persistent: Option<RefCell<Persistent>>,
+    is_builtin: bool, // See 'markdown.rs', an executable takes priority
+    capabilities: Vec<String>,
+    limits: Limits,
+    args: Vec<String>, // From the handler map, appended to every call
+    captured_stderr: Option<Mutex<String>>, // See 'capture_stderr()'
 }
 
 type Output = Result<String, String>;
 
 impl FileApi {
     pub fn from_filename(api_dir: &str, extension: &str) -> Result<Self, String> {
-        let command = Path::new(api_dir).join(Path::new(extension));
+        let handler = HandlerMap::default().resolve(extension);
+        Self::from_handler(api_dir, &handler, Limits::default())
+    }
+
+    pub fn from_handler(api_dir: &str, handler: &Handler, limits: Limits) -> Result<Self, String> {
+        let command = Path::new(api_dir).join(Path::new(handler.name.as_str()));
+        let args = handler.args.clone();
         if command.is_file() {
+            let capabilities = query_capabilities(command.as_path(), &args);
+            let persistent = if capabilities.iter().any(|c| c == "persistent") {
+                let persistent = Persistent::start(command.as_path(), &args, limits)?;
+                Some(RefCell::new(persistent))
+            } else {
+                None
+            };
             Ok(Self {
                 pathbuf: command,
+                persistent,
+                is_builtin: false,
+                capabilities,
+                limits,
+                args,
+                captured_stderr: None,
+            })
+        } else if cfg!(feature = "markdown") && handler.name == "md" {
+            Ok(Self {
+                pathbuf: PathBuf::from(BUILTIN_MARKDOWN),
+                persistent: None,
+                is_builtin: true,
+                capabilities: DEFAULT_CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+                limits,
+                args,
+                captured_stderr: None,
             })
         } else {
             Err([
                 "Cannot find API handler for ",
-                extension.escape().as_str(),
+                handler.name.escape().as_str(),
                 " not found.\nCannot read the file ",
                 command.to_string_lossy().escape().as_str(),
             ]
@@ -28,95 +246,660 @@ impl FileApi {
         }
     }
 
+    pub fn handler(&self) -> &Path {
+        self.pathbuf.as_path()
+    }
+
+    // Changes when the handler is edited (or the binary for the built-in one)
+    pub fn identity(&self) -> String {
+        if self.is_builtin {
+            return [BUILTIN_MARKDOWN, " ", env!("CARGO_PKG_VERSION")].join("");
+        }
+        let metadata = std::fs::metadata(self.handler()).ok();
+        let mtime = metadata.as_ref().and_then(|m| m.modified().ok());
+        format!(
+            "{} {:?} {} {:?}",
+            self.handler().to_string_lossy(),
+            self.args,
+            metadata.map(|m| m.len()).unwrap_or(0),
+            mtime,
+        )
+    }
+
+    // Keeps STDERR instead of showing it, until 'take_stderr()'
+    pub fn capture_stderr(&mut self) {
+        self.captured_stderr = Some(Mutex::default());
+    }
+
+    pub fn take_stderr(&self) -> String {
+        self.captured_stderr
+            .as_ref()
+            .map(|captured| std::mem::take(&mut *captured.lock().unwrap()))
+            .unwrap_or_default()
+    }
+
+    pub fn is_persistent(&self) -> bool {
+        self.persistent.is_some()
+    }
+
+    pub fn capabilities(&self) -> &[String] {
+        &self.capabilities
+    }
+
+    pub fn supports(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+
+    fn run(&self, stdin: Option<&[&str]>, args: &[&str], context: Option<&Context>) -> Output {
+        let env = context.map(|c| c.vars().to_vec()).unwrap_or_default();
+        let prefix = context
+            .map(|c| stderr_prefix(c.post_id, c.lang))
+            .unwrap_or_default();
+        let stderr_to = match &self.captured_stderr {
+            Some(captured) => Stderr::Captured(captured),
+            None => Stderr::Shown(&prefix),
+        };
+        let builtin_args = args;
+        let args = args.iter().copied();
+        let args = args.chain(self.args.iter().map(String::as_str));
+        let args = &args.collect::<Vec<_>>();
+        match &self.persistent {
+            _ if self.is_builtin => builtin_run(stdin, builtin_args),
+            Some(persistent) => {
+                let mut persistent = persistent.borrow_mut();
+                persistent.request(self.handler(), stdin, args, &env, stderr_to, context)
+            }
+            None => {
+                let handler = self.handler();
+                command_run(handler, stdin, args, &env, stderr_to, context, self.limits)
+            }
+        }
+    }
+
     // These three lines are the what each file extension API must implement
     #[inline]
     pub fn comment(&self) -> Output {
-        command_run(self.pathbuf.as_path(), None, &["comment"])
+        self.run(None, &["comment"], None)
     }
     #[inline]
-    pub fn compile(&self, stdin: &[&str], domain: &str, toc_location: &str, body_location: &str) -> Output {
-        command_run(
-            self.pathbuf.as_path(),
+    pub fn compile(
+        &self,
+        stdin: &[&str],
+        domain: &str,
+        toc_location: &str,
+        body_location: &str,
+        context: &Context,
+    ) -> Output {
+        self.run(
             Some(stdin),
             &["compile", domain, toc_location, body_location],
+            Some(context),
         )
     }
     #[inline]
-    pub fn frontmatter(&self, stdin: &[&str]) -> Output {
-        command_run(
-            self.pathbuf.as_path(),
-            Some(stdin),
-            &["frontmatter"],
-        )
+    pub fn frontmatter(&self, stdin: &[&str], context: &Context) -> Output {
+        if self.supports("frontmatter") {
+            self.run(Some(stdin), &["frontmatter"], Some(context))
+        } else {
+            Ok(String::new())
+        }
     }
 }
 
-pub fn command_run(cmd_path: &Path, stdin: Option<&[&str]>, args: &[&str]) -> Output {
-    let mut child = Command::new(cmd_path)
+// Handlers that do not know 'CAPABILITIES_ARG' probably error, so ignore STDERR
+fn query_capabilities(cmd_path: &Path, args: &[String]) -> Vec<String> {
+    let stdout = Command::new(cmd_path)
+        .arg(CAPABILITIES_ARG)
         .args(args)
-        .stdin(if stdin.is_some() {
-            Stdio::piped()
-        } else {
-            Stdio::null()
-        })
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()
-        .map_err(|err| {
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).to_string())
+        .unwrap_or_default();
+    let first_line = stdout.lines().next().unwrap_or("");
+    match first_line.trim_start().strip_prefix(CAPABILITIES_MARKER) {
+        Some(list) => return list.split_whitespace().map(str::to_string).collect(),
+        // e.g. usage text, which would otherwise drop 'frontmatter'
+        None if !stdout.trim().is_empty() => eprintln!(
+            "Warning: {} printed something other than '{} ...' for '{}', using the defaults: {}",
+            cmd_path.to_string_lossy().escape(),
+            CAPABILITIES_MARKER,
+            CAPABILITIES_ARG,
+            DEFAULT_CAPABILITIES.join(" "),
+        ),
+        None => {}
+    }
+    DEFAULT_CAPABILITIES.iter().map(|c| c.to_string()).collect()
+}
+
+#[cfg(feature = "markdown")]
+fn builtin_run(stdin: Option<&[&str]>, args: &[&str]) -> Output {
+    let sections = stdin.unwrap_or(&[]);
+    match args {
+        ["comment"] => Ok(markdown::COMMENT.to_string()),
+        ["frontmatter"] => Ok(markdown::frontmatter(sections)),
+        ["compile", _domain, toc_location, body_location] => {
+            markdown::compile(sections, toc_location, body_location)
+        }
+        _ => Err([
+            BUILTIN_MARKDOWN,
+            " does not support ",
+            args.join(" ").escape().as_str(),
+        ]
+        .join("")),
+    }
+}
+
+#[cfg(not(feature = "markdown"))]
+fn builtin_run(_: Option<&[&str]>, _: &[&str]) -> Output {
+    unreachable!("'is_builtin' is only set with the 'markdown' feature")
+}
+
+#[derive(Debug)]
+struct Persistent {
+    child: Child,
+    stdin: Option<ChildStdin>, // Option so that 'drop()' can close it first
+    stdout: BufReader<ChildStdout>,
+    limits: Limits,
+}
+
+impl Persistent {
+    fn start(cmd_path: &Path, args: &[String], limits: Limits) -> Result<Self, String> {
+        let mut command = Command::new(cmd_path);
+        command
+            .arg(SERVE_ARG)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit()); // STDERR is framed, this is for strays
+        set_process_group(&mut command, limits);
+        let mut child = command.spawn().map_err(|err| {
             [
-                "Error starting the executable ",
+                "Error starting the persistent handler ",
                 cmd_path.to_string_lossy().escape().as_str(),
                 "\n",
                 err.to_string().as_str(),
             ]
             .join("")
         })?;
+        Ok(Self {
+            stdin: child.stdin.take(),
+            stdout: BufReader::new(child.stdout.take().unwrap()),
+            child,
+            limits,
+        })
+    }
 
-    // Write the stdin if requires stdin
-    if let Some(text_parts) = stdin {
-        child
-            .stdin
-            .as_mut()
-            .map(|handle| {
-                for part in text_parts {
-                    if let Err(err) = handle.write_all(part.as_bytes()) {
-                        return Err(err.to_string());
+    fn request(
+        &mut self,
+        cmd_path: &Path,
+        stdin: Option<&[&str]>,
+        args: &[&str],
+        env: &[(&str, &str)],
+        stderr_to: Stderr,
+        context: Option<&Context>,
+    ) -> Output {
+        let parts = stdin.unwrap_or(&[]);
+        let stdin_len = parts.iter().map(|part| part.len()).sum::<usize>();
+        let mut request = Vec::with_capacity(stdin_len + 64);
+        request.extend_from_slice(args.len().to_string().as_bytes());
+        request.push(b'\n');
+        for arg in args {
+            push_frame_header(&mut request, arg.len());
+            request.extend_from_slice(arg.as_bytes());
+        }
+        request.extend_from_slice(env.len().to_string().as_bytes());
+        request.push(b'\n');
+        for (key, value) in env {
+            push_frame_header(&mut request, key.len() + '='.len_utf8() + value.len());
+            request.extend_from_slice(key.as_bytes());
+            request.push(b'=');
+            request.extend_from_slice(value.as_bytes());
+        }
+        push_frame_header(&mut request, stdin_len);
+        for part in parts {
+            request.extend_from_slice(part.as_bytes());
+        }
+
+        let error = |err: String| {
+            [
+                "Persistent handler ",
+                cmd_path.to_string_lossy().escape().as_str(),
+                " failed on '",
+                args.first().unwrap_or(&""),
+                "'.\n",
+                err.as_str(),
+            ]
+            .join("")
+        };
+
+        // A blocked read cannot be polled, so a watchdog kills the handler
+        // instead, which closes its STDOUT and ends the read
+        let is_timed_out = Arc::new(AtomicBool::new(false));
+        let (done, watchdog) = match self.limits.timeout {
+            Some(timeout) => {
+                let (done, wait_for_done) = mpsc::channel::<()>();
+                let pid = self.child.id();
+                let is_timed_out = Arc::clone(&is_timed_out);
+                let watchdog = thread::spawn(move || {
+                    if let Err(mpsc::RecvTimeoutError::Timeout) =
+                        wait_for_done.recv_timeout(timeout)
+                    {
+                        is_timed_out.store(true, Ordering::SeqCst);
+                        kill_process_group(pid);
                     }
+                });
+                (Some(done), Some(watchdog))
+            }
+            None => (None, None),
+        };
+
+        let max = self.limits.max_output;
+        let handle = self.stdin.as_mut().unwrap();
+        let result = handle
+            .write_all(&request)
+            .and_then(|_| handle.flush())
+            .map_err(|err| err.to_string())
+            .and_then(|_| {
+                let status = read_frame(&mut self.stdout, None)?;
+                let stdout = read_frame(&mut self.stdout, max)?;
+                let stderr = read_frame(&mut self.stdout, max)?;
+                Ok((status, stdout, stderr))
+            });
+        drop(done);
+        if let Some(watchdog) = watchdog {
+            let _ = watchdog.join();
+        }
+
+        if is_timed_out.load(Ordering::SeqCst) {
+            let _ = self.child.wait();
+            return Err(timeout_error(cmd_path, args, self.limits));
+        }
+        let (status, stdout, stderr) = result.map_err(error)?;
+        let code = String::from_utf8_lossy(&status).trim().parse::<i32>().ok();
+        // Framed, so shown after the fact rather than live
+        let mut lines = BufReader::new(stderr.as_slice());
+        write_prefixed_lines(&mut lines, stderr_to, context, None);
+        to_output(cmd_path, code, stdout, stderr_to)
+    }
+}
+
+impl Drop for Persistent {
+    // Closing STDIN is the signal for the handler to exit, and one that does
+    // not is killed after the timeout so that it cannot hang the build
+    fn drop(&mut self) {
+        self.stdin.take();
+        let deadline = Instant::now() + self.limits.timeout.unwrap_or(EXIT_GRACE);
+        let mut poll = POLL_MIN;
+        while let Ok(None) = self.child.try_wait() {
+            if Instant::now() >= deadline {
+                if self.limits.is_limited() {
+                    kill_process_group(self.child.id());
+                } else {
+                    let _ = self.child.kill();
                 }
-                Ok(())
-            })
-            .unwrap_or_else(|| Err("Cannot get handle to STDIN.".to_string()))
-            .map_err(|err| {
-                [
-                    "Trouble writing to the STDIN of the ",
-                    cmd_path.to_string_lossy().escape().as_str(),
-                    " command.",
-                    "\n",
-                    err.as_str(),
-                ]
-                .join("")
-            })?;
+                let _ = self.child.wait();
+                return;
+            }
+            thread::sleep(poll);
+            poll = (poll * 2).min(POLL_MAX);
+        }
+    }
+}
+
+fn push_frame_header(buffer: &mut Vec<u8>, len: usize) {
+    buffer.extend_from_slice(len.to_string().as_bytes());
+    buffer.push(b'\n');
+}
+
+fn read_frame<R: BufRead>(reader: &mut R, max: Option<usize>) -> Result<Vec<u8>, String> {
+    let mut header = Vec::new();
+    reader
+        .read_until(b'\n', &mut header)
+        .map_err(|err| err.to_string())?;
+    if header.last() != Some(&b'\n') {
+        return Err("The handler closed its STDOUT before responding".to_string());
+    }
+    let header = String::from_utf8_lossy(&header[..header.len() - 1]).to_string();
+    let len = header
+        .trim()
+        .parse::<usize>()
+        .map_err(|_| ["Invalid frame length ", header.escape().as_str()].join(""))?;
+    if let Some(max) = max.filter(|max| len > *max) {
+        return Err(format!(
+            "The output of {} bytes is over the limit of {}",
+            len, max
+        ));
     }
+    let mut frame = vec![0; len];
+    reader
+        .read_exact(&mut frame)
+        .map_err(|err| err.to_string())?;
+    Ok(frame)
+}
 
-    let output = child.wait_with_output().map_err(|err| {
+pub fn command_run(
+    cmd_path: &Path,
+    stdin: Option<&[&str]>,
+    args: &[&str],
+    env: &[(&str, &str)],
+    stderr_to: Stderr,
+    context: Option<&Context>, // To map line numbers in STDERR to the source
+    limits: Limits,
+) -> Output {
+    let mut command = Command::new(cmd_path);
+    command
+        .args(args)
+        .envs(env.iter().copied())
+        .stdin(if stdin.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    set_process_group(&mut command, limits);
+    let mut child = command.spawn().map_err(|err| {
         [
-            "The executable ",
+            "Error starting the executable ",
             cmd_path.to_string_lossy().escape().as_str(),
-            " could not run.\n",
+            "\n",
             err.to_string().as_str(),
         ]
         .join("")
     })?;
-    if output.status.success() {
-        std::io::stderr().write_all(&output.stderr).map_err(|err| {
+
+    // Feed STDIN while draining STDOUT and STDERR, otherwise a handler that
+    // writes before it has read all its input can fill both pipes and hang
+    let child_stdin = child.stdin.take();
+    let child_stdout = child.stdout.take().unwrap();
+    let child_stderr = child.stderr.take().unwrap();
+    let is_over_limit = AtomicBool::new(false);
+    let deadline = limits.timeout.map(|timeout| Instant::now() + timeout);
+    thread::scope(|scope| {
+        let writer = scope.spawn(|| write_stdin(child_stdin, stdin.unwrap_or(&[])));
+        let stdout = scope.spawn(|| read_stdout(child_stdout, limits, &is_over_limit));
+        let stderr = scope.spawn(|| {
+            let mut lines = BufReader::new(child_stderr);
+            if !write_prefixed_lines(&mut lines, stderr_to, context, limits.max_output) {
+                is_over_limit.store(true, Ordering::SeqCst);
+            }
+        });
+
+        let status =
+            wait_within_limits(&mut child, cmd_path, args, limits, deadline, &is_over_limit)?;
+
+        // Like 'wait_with_output()', this also waits on grandchildren with the
+        // pipes (e.g. 'cmd &'), but only until the deadline
+        let mut poll = POLL_MIN;
+        while !(writer.is_finished() && stdout.is_finished() && stderr.is_finished()) {
+            if deadline.map(|d| Instant::now() >= d).unwrap_or(false) {
+                kill_process_group(child.id()); // Closes the pipes for the joins
+                return Err(timeout_error(cmd_path, args, limits));
+            }
+            thread::sleep(poll);
+            poll = (poll * 2).min(POLL_MAX);
+        }
+        let stdout = stdout.join().unwrap_or_default();
+        let written = writer.join().unwrap_or(Ok(()));
+        let output = to_output(cmd_path, status.code(), stdout, stderr_to)?;
+        written.map_err(|err| {
+            [
+                "Trouble writing to the STDIN of the ",
+                cmd_path.to_string_lossy().escape().as_str(),
+                " command.",
+                "\n",
+                err.to_string().as_str(),
+            ]
+            .join("")
+        })?;
+        Ok(output)
+    })
+}
+
+// Also closes STDIN so the child knows the input is over
+fn write_stdin(handle: Option<ChildStdin>, text_parts: &[&str]) -> std::io::Result<()> {
+    if let Some(mut handle) = handle {
+        for part in text_parts {
+            handle.write_all(part.as_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+// Reads up to one byte past 'limits.max_output' to know if it was exceeded
+fn read_stdout(pipe: ChildStdout, limits: Limits, is_over_limit: &AtomicBool) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    let max = limits.max_output.map(|max| max as u64 + 1);
+    let _ = pipe.take(max.unwrap_or(u64::MAX)).read_to_end(&mut buffer);
+    if limits
+        .max_output
+        .map(|max| buffer.len() > max)
+        .unwrap_or(false)
+    {
+        is_over_limit.store(true, Ordering::SeqCst);
+    }
+    buffer
+}
+
+// Returns false if more than 'max' bytes were read (the rest is not read)
+// Locks STDERR per line so that lines of different processes do not mix
+fn write_prefixed_lines<R: BufRead>(
+    reader: &mut R,
+    stderr: Stderr,
+    context: Option<&Context>,
+    max: Option<usize>,
+) -> bool {
+    let mut line = Vec::new();
+    let mut total = 0;
+    loop {
+        line.clear();
+        match reader.read_until(b'\n', &mut line) {
+            Ok(0) | Err(_) => return true,
+            Ok(len) => total += len,
+        }
+        if max.map(|max| total > max).unwrap_or(false) {
+            return false;
+        }
+        if line.last() != Some(&b'\n') {
+            line.push(b'\n');
+        }
+        if let (Some(context), Ok(text)) = (context, std::str::from_utf8(&line)) {
+            line = to_source_rows(text, context).into_bytes();
+        }
+        let prefix = match stderr {
+            Stderr::Shown(prefix) => prefix,
+            Stderr::Captured(captured) => {
+                captured
+                    .lock()
+                    .unwrap()
+                    .push_str(&String::from_utf8_lossy(&line));
+                continue;
+            }
+        };
+        let mut handle = std::io::stderr().lock();
+        let _ = handle
+            .write_all(prefix.as_bytes())
+            .and_then(|_| handle.write_all(&line));
+    }
+}
+
+// Handlers number the lines of the view they were piped, e.g. '<stdin>:12:'
+// or 'line 12', so these are changed to the row in the post. Rows of included
+// files are also listed at the end with the file
+fn to_source_rows(line: &str, context: &Context) -> String {
+    let mut buffer = String::with_capacity(line.len());
+    let mut included = Vec::new();
+    let mut end = 0; // Up to where 'line' was copied into 'buffer'
+    while let Some(offset) = line[end..].find(|c: char| c.is_ascii_digit()) {
+        let start = end + offset;
+        let len = line[start..]
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(line.len() - start);
+        let (before, number, after) = (
+            &line[..start],
+            &line[start..start + len],
+            &line[start + len..],
+        );
+        let located = (is_line_row(before, after) || is_file_row(before, after))
+            .then(|| number.parse::<usize>().ok())
+            .flatten()
+            .and_then(|view_row| context.locate(view_row));
+
+        buffer.push_str(&line[end..start]);
+        match located {
+            Some((path, row)) => {
+                buffer.push_str(row.to_string().as_str());
+                if path != context.source_path {
+                    included.push([path.escape().as_str(), ":", &row.to_string()].join(""));
+                }
+            }
+            None => buffer.push_str(number),
+        }
+        end = start + len;
+    }
+    buffer.push_str(&line[end..]);
+    if !included.is_empty() {
+        let has_newline = buffer.ends_with('\n');
+        buffer.truncate(buffer.trim_end_matches('\n').len());
+        buffer.push_str([" (in ", &included.join(", "), ")"].join("").as_str());
+        if has_newline {
+            buffer.push('\n');
+        }
+    }
+    buffer
+}
+
+// e.g. 'line 12' but not 'deadline 12' or 'line 12th'
+fn is_line_row(before: &str, after: &str) -> bool {
+    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+    let word_start = match before.len().checked_sub("line ".len()) {
+        Some(i) if before.is_char_boundary(i) => i,
+        _ => return false,
+    };
+    before[word_start..].eq_ignore_ascii_case("line ")
+        && !before[..word_start].ends_with(is_word)
+        && !after.starts_with(is_word)
+}
+
+// e.g. '<stdin>:12:' anywhere, or 'post.adoc:12:' at the start of the line,
+// but not the '34' of the time '12:34:56'
+fn is_file_row(before: &str, after: &str) -> bool {
+    let file = match before.strip_suffix(':') {
+        Some(file) if after.starts_with(':') => file,
+        _ => return false,
+    };
+    file.ends_with("<stdin>")
+        || file == "-"
+        || (file.contains(['.', '/'])
+            && !file.starts_with(|c: char| c.is_ascii_digit())
+            && !file.contains(|c: char| c.is_whitespace() || c == ':'))
+}
+
+fn wait_within_limits(
+    child: &mut Child,
+    cmd_path: &Path,
+    args: &[&str],
+    limits: Limits,
+    deadline: Option<Instant>,
+    is_over_limit: &AtomicBool,
+) -> Result<ExitStatus, String> {
+    let mut poll = POLL_MIN;
+    loop {
+        let status = child.try_wait().map_err(|err| {
             [
-                "Could to write to stderr while executing ",
+                "The executable ",
                 cmd_path.to_string_lossy().escape().as_str(),
+                " could not run.\n",
                 err.to_string().as_str(),
             ]
             .join("")
         })?;
-        String::from_utf8(output.stdout).map_err(|_| {
+        if let Some(status) = status {
+            return Ok(status);
+        }
+        if is_over_limit.load(Ordering::SeqCst) {
+            kill_process_group(child.id());
+            let _ = child.wait();
+            return Err([
+                "The executable ",
+                cmd_path.to_string_lossy().escape().as_str(),
+                " was killed for writing more than ",
+                limits.max_output.unwrap_or(0).to_string().as_str(),
+                " bytes while running '",
+                subcommand(args),
+                "'",
+            ]
+            .join(""));
+        }
+        if deadline.map(|d| Instant::now() >= d).unwrap_or(false) {
+            kill_process_group(child.id());
+            let _ = child.wait();
+            return Err(timeout_error(cmd_path, args, limits));
+        }
+        thread::sleep(poll);
+        poll = (poll * 2).min(POLL_MAX);
+    }
+}
+
+fn timeout_error(cmd_path: &Path, args: &[&str], limits: Limits) -> String {
+    [
+        "The executable ",
+        cmd_path.to_string_lossy().escape().as_str(),
+        " timed out after ",
+        format!("{:?}", limits.timeout.unwrap_or_default()).as_str(),
+        " while running '",
+        subcommand(args),
+        "'",
+    ]
+    .join("")
+}
+
+// For errors, the linker's args (e.g. the frontmatter) are too long to print
+fn subcommand<'a>(args: &[&'a str]) -> &'a str {
+    args.first()
+        .and_then(|arg| arg.lines().next())
+        .unwrap_or("")
+}
+
+#[cfg(unix)]
+fn set_process_group(command: &mut Command, limits: Limits) {
+    use std::os::unix::process::CommandExt;
+    if limits.is_limited() {
+        command.process_group(0);
+    }
+}
+
+#[cfg(not(unix))]
+fn set_process_group(_: &mut Command, _: Limits) {}
+
+// Only called for processes started with 'set_process_group()'
+#[cfg(unix)]
+fn kill_process_group(pid: u32) {
+    extern "C" {
+        fn kill(pid: i32, sig: i32) -> i32;
+    }
+    const SIGKILL: i32 = 9;
+    // SAFETY: 'kill()' has no memory safety requirements. The process group
+    // id is the pid of its leader, which is not reused while it is unreaped
+    // or while the group still has members
+    unsafe { kill(-(pid as i32), SIGKILL) };
+}
+
+#[cfg(not(unix))]
+fn kill_process_group(pid: u32) {
+    let _ = Command::new("taskkill")
+        .args(["/F", "/T", "/PID", pid.to_string().as_str()])
+        .status();
+}
+
+// 'code' is None if interrupted
+// STDERR has already been shown or captured with 'write_prefixed_lines()'
+fn to_output(cmd_path: &Path, code: Option<i32>, stdout: Vec<u8>, stderr: Stderr) -> Output {
+    if code == Some(0) {
+        String::from_utf8(stdout).map_err(|_| {
             [
                 cmd_path.to_string_lossy().escape().as_str(),
                 " had invalid UTF8. We only support posts encoded in UTF8.",
@@ -128,16 +911,123 @@ pub fn command_run(cmd_path: &Path, stdin: Option<&[&str]>, args: &[&str]) -> Ou
             "Error while executing ",
             cmd_path.to_string_lossy().escape().as_str(),
             "\nError code: ",
-            match output.status.code() {
+            match code {
                 Some(code) => code.to_string(),
                 _ => "Interrupted".to_string(),
             }
             .as_str(),
             "\n=== STDOUT ===\n",
-            String::from_utf8_lossy(&output.stdout).to_string().as_str(),
+            String::from_utf8_lossy(&stdout).to_string().as_str(),
             "\n=== STDERR ===\n",
-            String::from_utf8_lossy(&output.stderr).to_string().as_str(),
+            match stderr {
+                Stderr::Shown(_) => Cow::Borrowed("(shown above)"),
+                Stderr::Captured(captured) => Cow::Owned(captured.lock().unwrap().clone()),
+            }
+            .as_ref(),
         ]
         .join(""))
     }
 }
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn source_rows() {
+        let view = PostView {
+            lang: None,
+            body: vec!["a\nb\nc\n"],
+            rows: vec![10],
+            excerpt: None,
+        };
+        let origins = Origins::default();
+        let context = Context {
+            lang: "",
+            post_id: "post",
+            source_path: "post.adoc",
+            other_langs: "",
+            cache_dir: "",
+            lines: Some((&view, &origins)),
+        };
+        let map = |line| to_source_rows(line, &context);
+        assert_eq!(map("<stdin>:2: error"), "<stdin>:11: error");
+        assert_eq!(map("x: WARNING: <stdin>:3: y"), "x: WARNING: <stdin>:12: y");
+        assert_eq!(map("post.adoc:1:5: error"), "post.adoc:10:5: error");
+        assert_eq!(map("<stdin>: line 2: error"), "<stdin>: line 11: error");
+        assert_eq!(map("Line 1, column 2"), "Line 10, column 2");
+
+        assert_eq!(map("at 12:01:02 error"), "at 12:01:02 error");
+        assert_eq!(map("12:01:02 error"), "12:01:02 error");
+        assert_eq!(map("2020-01-01T12:01:02Z"), "2020-01-01T12:01:02Z");
+        assert_eq!(map("error: x:1: y"), "error: x:1: y");
+        assert_eq!(map("deadline 3"), "deadline 3");
+        assert_eq!(map("Timeline 2"), "Timeline 2");
+        assert_eq!(map("line 2nd"), "line 2nd");
+        assert_eq!(map("line 9"), "line 9"); // Not in the view
+    }
+
+    #[test]
+    fn capabilities() {
+        let cmd_path = Path::new("tests/api/capabilities");
+        let query = |arg: &str| query_capabilities(cmd_path, &[arg.to_string()]);
+        assert_eq!(query("marked"), vec!["comment", "compile"]);
+        assert!(query("marked-empty").is_empty());
+        assert_eq!(query("usage"), DEFAULT_CAPABILITIES);
+        assert_eq!(query("unmarked"), DEFAULT_CAPABILITIES);
+        assert_eq!(query("fails"), DEFAULT_CAPABILITIES);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn timeout() {
+        let limits = Limits {
+            timeout: Some(Duration::from_millis(500)),
+            max_output: None,
+        };
+        // The background 'sleep' keeps the pipes open after 'sh' is killed or
+        // exits, which must not hang the build
+        for script in ["sleep 30 & sleep 30", "sleep 30 & echo done"] {
+            let start = Instant::now();
+            let run = command_run(
+                Path::new("sh"),
+                None,
+                &["-c", script],
+                &[],
+                Stderr::Shown(""),
+                None,
+                limits,
+            );
+            let err = run.unwrap_err();
+            assert!(err.contains("timed out"), "{}", err);
+            assert!(start.elapsed() < Duration::from_secs(10), "{}", script);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn persistent_ignoring_eof() {
+        let limits = Limits {
+            timeout: Some(Duration::from_millis(500)),
+            max_output: None,
+        };
+        let cmd_path = Path::new("tests/api/serve-ignores-eof");
+        let start = Instant::now();
+        let mut handler = Persistent::start(cmd_path, &[], limits).unwrap();
+        for _ in 0..2 {
+            let env = [("POLYGOT_LANG", "en")];
+            let run = handler.request(
+                cmd_path,
+                Some(&["a\n", "b"]),
+                &["compile", "x"],
+                &env,
+                Stderr::Shown(""),
+                None,
+            );
+            assert_eq!(run.unwrap(), "");
+        }
+        drop(handler); // Killed instead of waiting on the 'sleep 30'
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+}
@@ -1,8 +1,9 @@
 // 'date-created', 'date-updated', and
 // 'tags' has a special format
+// 'permalink' is an output format (see 'format()') that overrides the default
 // NOTE: 'filename', 'lang' are reserved
 use crate::custom_errors::ParseError;
-use crate::helpers::{parse_tags_and_push, program_name};
+use crate::helpers::{parse_tags_and_push, program_name, push_json_list, push_json_str};
 use crate::traits::{RangeExt, ShellEscape, VecExt};
 use chrono::{DateTime, Datelike, Utc};
 use std::borrow::Cow;
@@ -25,7 +26,10 @@ pub enum Value<'a> {
 
 // @TODO change year month day to date format
 // @FORMAT
-const KEY_BLACKLIST: [&str; 5] = ["file_stem", "lang", "year", "month", "day"];
+// The first 'PLACEHOLDER_COUNT' are filled in by 'format()' so cannot be set
+// The rest are output formats that can be set but not used as placeholders
+const KEY_BLACKLIST: [&str; 6] = ["file_stem", "lang", "year", "month", "day", "permalink"];
+const PLACEHOLDER_COUNT: usize = 5;
 
 #[derive(Debug)]
 pub struct Frontmatter<'frontmatter_string> {
@@ -49,6 +53,7 @@ impl<'frontmatter_string> Frontmatter<'frontmatter_string> {
         // For checking for duplicates
         let mut tag_list = Vec::new();
         let mut series_list = Vec::new();
+        let mut permalink_line = None;
 
         for (i, line) in frontmatter
             .lines()
@@ -59,7 +64,7 @@ impl<'frontmatter_string> Frontmatter<'frontmatter_string> {
             let key = &line[0..colon_index];
             let val_str = line[colon_index + ':'.len_utf8()..].trim();
 
-            if KEY_BLACKLIST.iter().any(|k| *k == key) {
+            if KEY_BLACKLIST[..PLACEHOLDER_COUNT].contains(&key) {
                 error_invalid(i + 1, line, key, "is reserved")?;
 
             } else if key_list.contains(&key) {
@@ -103,6 +108,9 @@ impl<'frontmatter_string> Frontmatter<'frontmatter_string> {
             } else if key == "series" {
                 parse_tags_and_push(&mut series_list, val_str, &[], true)
                     .map_err(|err| (i + 1, line, Cow::Owned(err)))?;
+            } else if KEY_BLACKLIST[PLACEHOLDER_COUNT..].contains(&key) {
+                Self::validate_format(val_str).map_err(|err| (i + 1, line, Cow::Owned(err)))?;
+                permalink_line = Some((i + 1, line));
             }
         }
         // Default have 'date-modified' and 'date-updated'
@@ -120,10 +128,20 @@ impl<'frontmatter_string> Frontmatter<'frontmatter_string> {
             tag_list.push("Untagged");
         }
         debug_assert_eq!(key_list.len(), value_list.len());
-        Ok(Self {
+        let frontmatter = Self {
             keys: key_list,
             values: value_list,
-        })
+        };
+
+        // Only now are all the keys it may use known
+        if let Some((row, line)) = permalink_line {
+            let permalink = frontmatter.permalink().unwrap();
+            let unknown = frontmatter.unknown_placeholders(permalink);
+            if !unknown.is_empty() {
+                return Err((row, line, Cow::Owned(unknown_error(permalink, &unknown))).into());
+            }
+        }
+        Ok(frontmatter)
     }
 
     // Emulate hashmap lookup with a Vec<(_, _)>
@@ -132,6 +150,14 @@ impl<'frontmatter_string> Frontmatter<'frontmatter_string> {
         Some(&self.values[i])
     }
 
+    // Already validated by 'Frontmatter::new()'
+    pub fn permalink(&self) -> Option<&'frontmatter_string str> {
+        match self.lookup("permalink") {
+            Some(Value::Utf8(s)) => Some(s),
+            _ => None,
+        }
+    }
+
     #[inline]
     fn pad_two<'a>(num: u32) -> Cow<'a, str> {
         let mut padded = String::with_capacity('0'.len_utf8() * 2);
@@ -142,11 +168,84 @@ impl<'frontmatter_string> Frontmatter<'frontmatter_string> {
         Cow::Owned(padded)
     }
 
-    pub fn format(&self, template: &str, file_stem: &str, lang: &str) -> String {
+    // Checks that are independent of any post, i.e. can be done up front
+    // Placeholders that are frontmatter keys are checked by 'unknown_placeholders()'
+    pub fn validate_format(template: &str) -> Result<(), String> {
+        let mut errors = Vec::new();
+        let mut rest = template;
+        while !rest.is_empty() {
+            let markup = Self::find_markup(rest);
+            let text = &rest[..markup.start];
+            let key = &rest[markup.clone()];
+            rest = &rest[markup.end..];
+
+            if text.contains('{') {
+                errors.push(["Unclosed '{' in ", text.escape().as_str()].join(""));
+            } else if text.contains('}') {
+                errors.push(["Unopened '}' in ", text.escape().as_str()].join(""));
+            } else if let Err(err) = validate_path_chars(text) {
+                errors.push(err);
+            }
+            if !key.is_empty() {
+                let key = &key['{'.len_utf8()..key.len() - '}'.len_utf8()];
+                if key.is_empty() || key.contains(|c: char| c.is_whitespace() || c == '{') {
+                    errors.push(["Invalid placeholder ", key.escape().as_str()].join(""));
+                } else if KEY_BLACKLIST[PLACEHOLDER_COUNT..].contains(&key) {
+                    let placeholder = ["{", key, "}"].join("");
+                    errors.push(["Cannot use the reserved placeholder ", &placeholder].join(""));
+                }
+            }
+        }
+        if template.starts_with('/') {
+            errors.push("Must be a relative path (cannot start with '/')".to_string());
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err([
+                "The output format ",
+                template.escape().as_str(),
+                " is invalid:\n- ",
+                errors.join("\n- ").as_str(),
+            ]
+            .join(""))
+        }
+    }
+
+    // Placeholders in 'template' that 'format()' cannot fill in, i.e. neither
+    // reserved placeholders nor (non-reserved) frontmatter keys
+    pub fn unknown_placeholders<'a>(&self, template: &'a str) -> Vec<&'a str> {
+        let mut unknown = Vec::new();
+        let mut rest = template;
+        while !rest.is_empty() {
+            let markup = Self::find_markup(rest);
+            let key = &rest[markup.clone()];
+            rest = &rest[markup.end..];
+
+            if !key.is_empty() {
+                let key = &key['{'.len_utf8()..key.len() - '}'.len_utf8()];
+                let is_known = KEY_BLACKLIST[..PLACEHOLDER_COUNT].contains(&key)
+                    || (!KEY_BLACKLIST.contains(&key) && self.lookup(key).is_some());
+                if !is_known {
+                    unknown.push(key);
+                }
+            }
+        }
+        unknown
+    }
+
+    // Assumes 'template' has passed 'validate_format()'
+    pub fn format(&self, template: &str, file_stem: &str, lang: &str) -> Result<String, String> {
+        let unknown = self.unknown_placeholders(template);
+        if !unknown.is_empty() {
+            return Err(unknown_error(template, &unknown));
+        }
+
         let range = 0..template.len();
         let count = range.split_over(template, Self::find_markup).count();
         let mut output = Vec::with_capacity(count * 2);
-        range.split_over(template, Self::find_markup).for_each(|x| {
+        for x in range.split_over(template, Self::find_markup) {
             let text = x.0.of(template);
             let key = x.1.of(template);
             let key = if key.is_empty() {
@@ -159,6 +258,7 @@ impl<'frontmatter_string> Frontmatter<'frontmatter_string> {
             // @TODO allow for date format
             // VOLATILE: Sync with key_blacklist
             let value = match key {
+                "" => Cow::Borrowed(""),
                 "year" | "month" | "day" => match self.lookup("date-created") {
                     Some(Value::Utf8(_)) => unreachable!("Always stored as a date"),
                     Some(Value::DateTime(x)) => match key {
@@ -167,20 +267,23 @@ impl<'frontmatter_string> Frontmatter<'frontmatter_string> {
                         "day" => Self::pad_two(x.day()),
                         _ => unreachable!(),
                     },
-                    None => todo!("Will add defaults to be taken in from file"),
+                    None => unreachable!("'Frontmatter::new()' always adds 'date-created'"),
                 },
                 "lang" => Cow::Borrowed(lang),
                 "file_stem" => Cow::Borrowed(file_stem),
                 _ => match self.lookup(key) {
-                    Some(Value::Utf8(x)) => Cow::Borrowed(*x),
-                    Some(Value::DateTime(_)) => todo!(),
-                    None => Cow::Borrowed(""),
+                    Some(Value::Utf8(x)) => {
+                        validate_path_chars(x)?;
+                        Cow::Borrowed(*x)
+                    }
+                    Some(Value::DateTime(x)) => Cow::Owned(x.format("%Y-%m-%d").to_string()),
+                    None => unreachable!("Checked by 'unknown_placeholders()'"),
                 },
             };
             output.push_and_check(Cow::Borrowed(text));
             output.push_and_check(value);
-        });
-        output.join("")
+        }
+        Ok(output.join(""))
     }
 
     // post, frontmatter, lang => filename (check all filepath limits?) => filepathk
@@ -276,6 +379,28 @@ impl<'frontmatter_string> Frontmatter<'frontmatter_string> {
         // join should be allocating the right size, probably
         meta_keyvals.join("")
     }
+
+    // Same data as 'serialise()' but as a JSON object for '--linker-stdin'
+    // 'tags' and 'series' are sent as lists, everything else as strings
+    pub fn serialise_json(&self, buffer: &mut String) {
+        debug_assert_eq!(self.keys.len(), self.values.len());
+        buffer.push('{');
+        for (i, (key, val)) in self.keys.iter().zip(self.values.iter()).enumerate() {
+            if i > 0 {
+                buffer.push(',');
+            }
+            push_json_str(buffer, key);
+            buffer.push(':');
+            match (*key, val) {
+                ("tags", Value::Utf8(s)) | ("series", Value::Utf8(s)) => {
+                    push_json_list(buffer, s.split_whitespace())
+                }
+                (_, Value::Utf8(s)) => push_json_str(buffer, s),
+                (_, Value::DateTime(dt)) => push_json_str(buffer, dt.to_rfc2822().as_str()),
+            }
+        }
+        buffer.push('}');
+    }
 }
 
 // @MARKUP_RULE
@@ -298,10 +423,40 @@ fn validate_and_count(frontmatter: &str) -> Result<usize, ParseError> {
     Ok(count)
 }
 
+// Characters that are a problem for either file systems, URLs, or our caches
+// ('/' is allowed as output formats are paths)
+const PATH_BLACKLIST: [char; 10] = ['\\', ':', ',', '*', '?', '"', '<', '>', '|', '#'];
+
+fn validate_path_chars(text: &str) -> Result<(), String> {
+    let is_unsafe = text.contains(&PATH_BLACKLIST[..])
+        || text.contains(char::is_whitespace)
+        || text.contains(char::is_control)
+        || text.split('/').any(|segment| segment == "..");
+    if is_unsafe {
+        Err(format!(
+            "{} has unsafe characters. {:?}, whitespace, and '..' are not allowed",
+            text.escape(),
+            PATH_BLACKLIST
+        ))
+    } else {
+        Ok(())
+    }
+}
+
 /*******************************************************************************
  * Error messages
  ******************************************************************************/
 #[inline]
+fn unknown_error(template: &str, unknown: &[&str]) -> String {
+    [
+        "The output format ",
+        template.escape().as_str(),
+        " has placeholders that are neither reserved nor in the frontmatter: ",
+        unknown.join(", ").as_str(),
+    ]
+    .join("")
+}
+
 fn error_invalid<'a>(
     row: usize,
     line: &'a str,
@@ -340,8 +495,8 @@ fn error_invalid<'a>(
 #[cfg(test)]
 mod frontmatter_test {
     use super::*;
-    use crate::fileapi::FileApi;
-    use crate::post::Post;
+    use crate::fileapi::{Context, FileApi};
+    use crate::post::{LangGroups, Post};
     use chrono::Utc;
 
     #[test]
@@ -350,11 +505,11 @@ mod frontmatter_test {
         let pathstr = "config/published/chinese_tones.adoc";
 
         let file = std::fs::read_to_string(pathstr).unwrap();
-        let post = Post::new(&file, "//").unwrap();
+        let post = Post::new(&file, "//", None, &LangGroups::default()).unwrap();
         post.views.iter().for_each(|view| {
             let now = Utc::now();
             let lang = view.lang.unwrap_or("");
-            let fms = api.frontmatter(&view.body).unwrap();
+            let fms = api.frontmatter(&view.body, &Context::default()).unwrap();
             let frontmatter = Frontmatter::new(&fms, now, now).unwrap();
             println!(
                 "{:?}",
@@ -363,4 +518,58 @@ mod frontmatter_test {
         });
         //println!("{:?}", post);
     }
+
+    #[test]
+    fn format_test() {
+        assert!(Frontmatter::validate_format("blog/{lang}/{year}-{file_stem}.html").is_ok());
+        assert!(Frontmatter::validate_format("blog/{lang/{file_stem}.html").is_err());
+        assert!(Frontmatter::validate_format("blog/lang}/{file_stem}.html").is_err());
+        assert!(Frontmatter::validate_format("blog/{}/{file_stem}.html").is_err());
+        assert!(Frontmatter::validate_format("/blog/{file_stem}.html").is_err());
+        assert!(Frontmatter::validate_format("blog/../{file_stem}.html").is_err());
+        assert!(Frontmatter::validate_format("blog/a b:{file_stem}.html").is_err());
+
+        let now = Utc::now();
+        let frontmatter = Frontmatter::new("title:Hello\nslug:hi\n", now, now).unwrap();
+        let date = now.format("%Y-%m-%d").to_string();
+        assert_eq!(
+            frontmatter.format("{lang}/{slug}-{date-updated}", "id", "en"),
+            Ok(["en/hi-", date.as_str()].join(""))
+        );
+        assert!(frontmatter.format("{lang}/{missing}", "id", "en").is_err());
+        assert!(frontmatter.format("{lang}/{title}", "id", "en").is_ok());
+
+        let frontmatter = Frontmatter::new("title:Hello World\n", now, now).unwrap();
+        assert!(frontmatter.format("{lang}/{title}", "id", "en").is_err());
+
+        let frontmatter = Frontmatter::new("permalink:about/{lang}.html\n", now, now).unwrap();
+        assert_eq!(frontmatter.permalink(), Some("about/{lang}.html"));
+        assert_eq!(
+            frontmatter.format(frontmatter.permalink().unwrap(), "id", "en"),
+            Ok("about/en.html".to_string())
+        );
+        assert!(Frontmatter::validate_format("{lang}/{permalink}").is_err());
+        assert!(frontmatter.format("{permalink}", "id", "en").is_err());
+        assert_eq!(
+            frontmatter.unknown_placeholders("{lang}/{permalink}"),
+            vec!["permalink"]
+        );
+        assert!(Frontmatter::new("permalink:{lang}/{permalink}\n", now, now).is_err());
+        assert!(Frontmatter::new("lang:en\n", now, now).is_err());
+        assert!(Frontmatter::new("permalink:/about/{lang\n", now, now).is_err());
+
+        // Every unknown placeholder is listed, and a permalink is checked on parse
+        assert_eq!(
+            frontmatter.unknown_placeholders("{a}/{lang}/{b}"),
+            vec!["a", "b"]
+        );
+        let err = Frontmatter::new("permalink:{x}/{title}/{y}\ntitle:Hi\n", now, now);
+        let err = err.unwrap_err();
+        assert!(
+            format!("{:?}", err).contains("frontmatter: x, y"),
+            "{:?}",
+            err
+        );
+        assert!(Frontmatter::new("permalink:{slug}.html\nslug:hi\n", now, now).is_ok());
+    }
 }
@@ -1,7 +1,12 @@
 use crate::traits::{ShellEscape, VecExt};
 use chrono::{DateTime, TimeZone, Utc};
 use filetime::FileTime;
-use std::{fs, io, path::Path, time::SystemTime};
+use std::{
+    fs, io,
+    path::Path,
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
 
 // @TODO test on windows
 pub const TAG_BLACKLIST: [char; 4] = [
@@ -48,6 +53,46 @@ pub fn parse_tags_and_push<'a>(
     Ok(tags_added)
 }
 
+// For the '@optional_with_arg' options that are numbers
+pub fn parse_num_option<T: FromStr>(value: Option<&str>, long: &str) -> Result<Option<T>, String>
+where
+    T::Err: std::fmt::Display,
+{
+    value
+        .map(|s| {
+            s.parse::<T>().map_err(|err| {
+                [
+                    "--",
+                    long,
+                    " ",
+                    s.escape().as_str(),
+                    " is not valid. ",
+                    err.to_string().as_str(),
+                ]
+                .join("")
+            })
+        })
+        .transpose()
+}
+
+pub fn parse_seconds_option(value: Option<&str>, long: &str) -> Result<Option<Duration>, String> {
+    parse_num_option::<f64>(value, long)?
+        .map(|seconds| {
+            Duration::try_from_secs_f64(seconds).map_err(|err| {
+                [
+                    "--",
+                    long,
+                    " ",
+                    value.unwrap().escape().as_str(),
+                    " is not valid. ",
+                    err.to_string().as_str(),
+                ]
+                .join("")
+            })
+        })
+        .transpose()
+}
+
 pub fn program_name() -> String {
     std::env::current_exe()
         .map(|pathbuf| {
@@ -242,3 +287,99 @@ pub fn create_parent_dir(location: &str) -> Result<(), String> {
 //    }
 //    Ok(())
 //}
+
+// Minimal JSON writing for the structured linker protocol (--linker-stdin)
+// Not pulling in serde for what is a handful of strings and lists
+pub fn push_json_str(buffer: &mut String, s: &str) {
+    buffer.reserve(s.len() + 2);
+    buffer.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => buffer.push_str("\\\""),
+            '\\' => buffer.push_str("\\\\"),
+            '\n' => buffer.push_str("\\n"),
+            '\r' => buffer.push_str("\\r"),
+            '\t' => buffer.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                buffer.push_str(format!("\\u{:04x}", c as u32).as_str());
+            }
+            c => buffer.push(c),
+        }
+    }
+    buffer.push('"');
+}
+
+pub fn push_json_list<'a, I: Iterator<Item = &'a str>>(buffer: &mut String, list: I) {
+    buffer.push('[');
+    for (i, entry) in list.enumerate() {
+        if i > 0 {
+            buffer.push(',');
+        }
+        push_json_str(buffer, entry);
+    }
+    buffer.push(']');
+}
+
+// A directory for a test, removed when dropped (even if the test panics)
+#[cfg(test)]
+pub struct TempDir(pub std::path::PathBuf);
+
+#[cfg(test)]
+impl TempDir {
+    // Tests run in parallel in one process, so the pid alone is not unique
+    pub fn new(name: &str) -> Self {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let count = COUNT.fetch_add(1, Ordering::SeqCst);
+        let dir = format!("polygot-{}-{}-{}", name, std::process::id(), count);
+        let path = std::env::temp_dir().join(dir);
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{push_json_list, push_json_str};
+
+    fn json_str(s: &str) -> String {
+        let mut buffer = String::new();
+        push_json_str(&mut buffer, s);
+        buffer
+    }
+
+    #[test]
+    fn json_escaping() {
+        assert_eq!(json_str(""), r#""""#);
+        assert_eq!(json_str(r#"say "hi""#), r#""say \"hi\"""#);
+        assert_eq!(json_str(r"C:\dir\"), r#""C:\\dir\\""#);
+        assert_eq!(json_str("a\nb\r\tc"), r#""a\nb\r\tc""#);
+        assert_eq!(
+            json_str("\u{0}\u{8}\u{1b}\u{1f}"),
+            r#""\u0000\u0008\u001b\u001f""#
+        );
+        // Only the C0 controls must be escaped, everything else passes through
+        assert_eq!(json_str("\u{7f} /"), "\"\u{7f} /\"");
+        assert_eq!(json_str("中文 é 😀"), "\"中文 é 😀\"");
+    }
+
+    #[test]
+    fn json_list() {
+        let list = |entries: &[&str]| {
+            let mut buffer = String::new();
+            push_json_list(&mut buffer, entries.iter().copied());
+            buffer
+        };
+        assert_eq!(list(&[]), "[]");
+        assert_eq!(list(&["en"]), r#"["en"]"#);
+        assert_eq!(list(&["en", "a\"b", "中"]), r#"["en","a\"b","中"]"#);
+    }
+}
@@ -0,0 +1,306 @@
+// Syntax highlighting of the compiled doc partials (with '--highlight')
+// Any handler that outputs '<pre><code class="language-X">' blocks gets
+// highlighting, without shelling out to e.g. pygments per view.
+//
+// Tokens are wrapped in '<span class="tok-*">' and coloured by the CSS from
+// the 'highlight-css' subcommand. This is a lexer, not a parser, so it only
+// knows keywords, strings, numbers, comments and (capitalised) types.
+// Blocks with markup inside (e.g. already highlighted) are left alone.
+
+use crate::text::{decode_entities, escape_html};
+
+struct Syntax {
+    keywords: &'static str, // Space separated
+    literals: &'static str,
+    line_comment: &'static [&'static str],
+    block_comment: Option<(&'static str, &'static str)>,
+    quotes: &'static [char],
+    is_rust: bool, // Lifetimes look like unterminated char literals
+}
+
+const C_LIKE: Syntax = Syntax {
+    keywords: "\
+        auto break case char const continue default do double else enum extern float for \
+        goto if inline int long register return short signed sizeof static struct switch \
+        typedef union unsigned void volatile while class namespace template typename \
+        public private protected virtual new delete this using",
+    literals: "true false NULL nullptr",
+    line_comment: &["//"],
+    block_comment: Some(("/*", "*/")),
+    quotes: &['"', '\''],
+    is_rust: false,
+};
+
+const RUST: Syntax = Syntax {
+    keywords: "\
+        as async await break const continue crate dyn else enum extern fn for if impl in \
+        let loop match mod move mut pub ref return self Self static struct super trait \
+        type unsafe use where while",
+    literals: "true false",
+    line_comment: &["//"],
+    block_comment: Some(("/*", "*/")),
+    quotes: &['"', '\''],
+    is_rust: true,
+};
+
+const JAVASCRIPT: Syntax = Syntax {
+    keywords: "\
+        async await break case catch class const continue default delete do else export \
+        extends finally for function if import in instanceof let new of return static \
+        switch this throw try typeof var void while yield interface type",
+    literals: "true false null undefined NaN",
+    line_comment: &["//"],
+    block_comment: Some(("/*", "*/")),
+    quotes: &['"', '\'', '`'],
+    is_rust: false,
+};
+
+const PYTHON: Syntax = Syntax {
+    keywords: "\
+        and as assert async await break class continue def del elif else except finally \
+        for from global if import in is lambda nonlocal not or pass raise return try \
+        while with yield",
+    literals: "True False None",
+    line_comment: &["#"],
+    block_comment: None,
+    quotes: &['"', '\''],
+    is_rust: false,
+};
+
+const SHELL: Syntax = Syntax {
+    keywords: "\
+        case do done elif else esac fi for function if in local return then until while \
+        export readonly shift exit",
+    literals: "true false",
+    line_comment: &["#"],
+    block_comment: None,
+    quotes: &['"', '\''],
+    is_rust: false,
+};
+
+fn syntax_for(lang: &str) -> Option<&'static Syntax> {
+    match lang {
+        "rust" | "rs" => Some(&RUST),
+        "c" | "h" | "cpp" | "c++" | "cc" | "java" | "go" | "json" => Some(&C_LIKE),
+        "javascript" | "js" | "typescript" | "ts" => Some(&JAVASCRIPT),
+        "python" | "py" => Some(&PYTHON),
+        "sh" | "shell" | "bash" | "zsh" | "console" => Some(&SHELL),
+        _ => None,
+    }
+}
+
+// Returns None if there was nothing to highlight
+pub fn highlight_html(html: &str) -> Option<String> {
+    let mut output = String::with_capacity(html.len() * 2);
+    let mut is_changed = false;
+    let mut rest = html;
+    while let Some((before, lang, code, after)) = next_code_block(rest) {
+        output.push_str(before);
+        match syntax_for(lang) {
+            Some(syntax) if !code.contains('<') => {
+                highlight(&mut output, decode_entities(code).as_str(), syntax);
+                is_changed = true;
+            }
+            _ => output.push_str(code),
+        }
+        rest = after;
+    }
+    output.push_str(rest);
+    is_changed.then_some(output)
+}
+
+// Returns (up to and including the '<code>' tag, lang, code, from '</code>')
+fn next_code_block(html: &str) -> Option<(&str, &str, &str, &str)> {
+    let mut cursor = 0;
+    loop {
+        let open = cursor + html[cursor..].find("<code")?;
+        let tag_end = open + html[open..].find('>')? + '>'.len_utf8();
+        cursor = tag_end;
+
+        // Must directly follow a '<pre>' tag
+        let is_in_pre = html[..open]
+            .trim_end()
+            .rsplit_once('<')
+            .map(|(_, tag)| tag.starts_with("pre") && tag.ends_with('>'))
+            .unwrap_or(false);
+        let lang = html[open..tag_end]
+            .split_once("class=\"")
+            .and_then(|(_, classes)| classes.split('"').next())
+            .and_then(|classes| {
+                let mut classes = classes.split_whitespace();
+                classes.find_map(|class| class.strip_prefix("language-"))
+            });
+        if let (true, Some(lang)) = (is_in_pre, lang) {
+            let close = tag_end + html[tag_end..].find("</code>")?;
+            return Some((
+                &html[..tag_end],
+                lang,
+                &html[tag_end..close],
+                &html[close..],
+            ));
+        }
+    }
+}
+
+fn highlight(buffer: &mut String, code: &str, syntax: &Syntax) {
+    let mut rest = code;
+    while let Some(c) = rest.chars().next() {
+        let prev = code[..code.len() - rest.len()].chars().next_back();
+        let (len, class) = if let Some(len) = comment_len(rest, prev, syntax) {
+            (len, Some("tok-com"))
+        } else if syntax.quotes.contains(&c) {
+            match string_len(rest, c, syntax) {
+                Some(len) => (len, Some("tok-str")),
+                None => (c.len_utf8(), None),
+            }
+        } else if c.is_ascii_digit() {
+            let len = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.'))
+                .unwrap_or(rest.len());
+            (len, Some("tok-num"))
+        } else if c.is_alphabetic() || c == '_' {
+            let len = rest
+                .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            let word = &rest[..len];
+            // Rust macros, e.g. 'println!'
+            let len = match rest[len..].starts_with('!') && syntax.is_rust {
+                true => len + '!'.len_utf8(),
+                false => len,
+            };
+            let class = if is_listed(syntax.keywords, word) {
+                Some("tok-kw")
+            } else if is_listed(syntax.literals, word) {
+                Some("tok-lit")
+            } else if len > word.len() {
+                Some("tok-mac")
+            } else if word.starts_with(char::is_uppercase) && syntax.is_rust {
+                Some("tok-ty")
+            } else {
+                None
+            };
+            (len, class)
+        } else {
+            (c.len_utf8(), None)
+        };
+
+        match class {
+            Some(class) => {
+                buffer.push_str("<span class=\"");
+                buffer.push_str(class);
+                buffer.push_str("\">");
+                escape_html(buffer, &rest[..len]);
+                buffer.push_str("</span>");
+            }
+            None => escape_html(buffer, &rest[..len]),
+        }
+        rest = &rest[len..];
+    }
+}
+
+fn is_listed(list: &str, word: &str) -> bool {
+    list.split_whitespace().any(|listed| listed == word)
+}
+
+// 'prev' is the character before 'text'
+fn comment_len(text: &str, prev: Option<char>, syntax: &Syntax) -> Option<usize> {
+    // '#' only starts a comment as its own word, unlike '${#x}', '$#' or 'a#b'
+    let is_word_start = prev.map(|c| c.is_whitespace() || c == ';').unwrap_or(true);
+    if syntax
+        .line_comment
+        .iter()
+        .any(|marker| text.starts_with(marker) && (*marker != "#" || is_word_start))
+    {
+        return Some(text.find('\n').unwrap_or(text.len()));
+    }
+    let (open, close) = syntax.block_comment?;
+    let after_open = text.strip_prefix(open)?;
+    Some(
+        after_open
+            .find(close)
+            .map(|end| open.len() + end + close.len())
+            .unwrap_or(text.len()),
+    )
+}
+
+// None if this quote does not start a string (e.g. a Rust lifetime)
+fn string_len(text: &str, quote: char, syntax: &Syntax) -> Option<usize> {
+    let after = &text[quote.len_utf8()..];
+    // 'a' and '\n' are chars, 'a is a lifetime
+    if syntax.is_rust && quote == '\'' {
+        let c = after.chars().next()?;
+        let end = match c {
+            '\\' => after.get(2..)?.find('\'')? + 2,
+            _ if after[c.len_utf8()..].starts_with('\'') => c.len_utf8(),
+            _ => return None,
+        };
+        return Some(quote.len_utf8() + end + '\''.len_utf8());
+    }
+
+    let mut is_escaped = false;
+    for (i, c) in after.char_indices() {
+        match c {
+            _ if is_escaped => is_escaped = false,
+            '\\' => is_escaped = true,
+            _ if c == quote => return Some(quote.len_utf8() + i + c.len_utf8()),
+            '\n' if quote != '`' => return Some(quote.len_utf8() + i),
+            _ => {}
+        }
+    }
+    Some(text.len()) // Unterminated
+}
+
+pub const CSS: &str = "\
+/* Generated by `polygot-posts highlight-css` */
+pre code .tok-kw  { color: #a626a4; font-weight: bold; }
+pre code .tok-ty  { color: #c18401; }
+pre code .tok-mac { color: #4078f2; }
+pre code .tok-str { color: #50a14f; }
+pre code .tok-num { color: #986801; }
+pre code .tok-lit { color: #986801; }
+pre code .tok-com { color: #a0a1a7; font-style: italic; }
+@media (prefers-color-scheme: dark) {
+  pre code .tok-kw  { color: #c678dd; }
+  pre code .tok-ty  { color: #e5c07b; }
+  pre code .tok-mac { color: #61afef; }
+  pre code .tok-str { color: #98c379; }
+  pre code .tok-num { color: #d19a66; }
+  pre code .tok-lit { color: #d19a66; }
+  pre code .tok-com { color: #7f848e; }
+}
+";
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn highlight_test() {
+        let html = "<p>x</p><pre><code class=\"language-rust\">\
+                    fn f&lt;'a&gt;() { let c = 'b'; println!(&quot;1&quot;); } // ok\
+                    </code></pre><pre><code>plain</code></pre>";
+        assert_eq!(
+            highlight_html(html).unwrap(),
+            "<p>x</p><pre><code class=\"language-rust\">\
+             <span class=\"tok-kw\">fn</span> f&lt;'a&gt;() { \
+             <span class=\"tok-kw\">let</span> c = <span class=\"tok-str\">'b'</span>; \
+             <span class=\"tok-mac\">println!</span>(<span class=\"tok-str\">&quot;1&quot;</span>); } \
+             <span class=\"tok-com\">// ok</span>\
+             </code></pre><pre><code>plain</code></pre>"
+        );
+
+        // Asciidoctor style, and already highlighted blocks are left alone
+        let html = "<pre class=\"highlight\"><code class=\"language-sh\" data-lang=\"sh\">\
+                    echo 'hi' # 2</code></pre>";
+        assert!(highlight_html(html).unwrap().contains(
+            "echo <span class=\"tok-str\">'hi'</span> <span class=\"tok-com\"># 2</span>"
+        ));
+        assert!(highlight_html("<pre><code class=\"language-sh\"><b>x</b></code></pre>").is_none());
+
+        // Not comments in shell
+        let html = "<pre><code class=\"language-sh\">echo ${#x} $# a#b;# c</code></pre>";
+        assert!(highlight_html(html)
+            .unwrap()
+            .contains("echo ${#x} $# a#b;<span class=\"tok-com\"># c</span>"));
+    }
+}
@@ -0,0 +1,209 @@
+// Splicing shared snippets (e.g. a disclaimer) into posts
+// A comment line like '// api_include: ../snippets/disclaimer.adoc' is
+// replaced by the file's contents before the post is split by language, so
+// the language markers inside the included file apply as if written in the
+// post. The contents are wrapped in a language scope so those markers do not
+// leak past the include. Paths are relative to the file with the directive,
+// and included files can include too.
+
+use crate::custom_errors::ParseError;
+use crate::post::{API_BEGIN_LANGUAGE, API_END_LANGUAGE};
+use crate::traits::ShellEscape;
+use chrono::{DateTime, TimeZone, Utc};
+use filetime::FileTime;
+use std::borrow::Cow;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const API_INCLUDE: &str = "api_include:";
+
+// Where the lines of the spliced text came from, as (first row in the
+// spliced text, file, row in the file) after which rows count up together
+#[derive(Debug, Default)]
+pub struct Origins(Vec<(usize, PathBuf, usize)>);
+
+impl Origins {
+    // The file and row of 'row' in the spliced text, which is the row in
+    // 'path' if nothing was included
+    pub fn locate<'a>(&'a self, row: usize, path: &'a Path) -> (&'a Path, usize) {
+        match self.0.iter().rev().find(|(start, _, _)| *start <= row) {
+            Some((start, file, file_row)) => (file.as_path(), file_row + row - start),
+            None => (path, row),
+        }
+    }
+}
+
+// Returns None if 'text' has no includes, otherwise the spliced text, the
+// last time any of the included files were modified, and the line origins
+pub fn splice(
+    text: &str,
+    comment_marker: &str,
+    path: &Path,
+) -> Result<Option<(String, DateTime<Utc>, Origins)>, String> {
+    if !text
+        .lines()
+        .any(|line| directive(line, comment_marker).is_some())
+    {
+        return Ok(None);
+    }
+    let mut splicer = Splicer {
+        buffer: String::with_capacity(text.len()),
+        row_count: 0,
+        updated: Utc.timestamp_opt(0, 0).unwrap(),
+        stack: vec![canonical(path)],
+        origins: Vec::new(),
+        comment_marker,
+    };
+    splicer.splice(text, path)?;
+    Ok(Some((
+        splicer.buffer,
+        splicer.updated,
+        Origins(splicer.origins),
+    )))
+}
+
+struct Splicer<'a> {
+    buffer: String,
+    row_count: usize, // Lines in 'buffer'
+    updated: DateTime<Utc>,
+    stack: Vec<PathBuf>, // The chain of files being included, for cycles
+    origins: Vec<(usize, PathBuf, usize)>,
+    comment_marker: &'a str,
+}
+
+impl<'a> Splicer<'a> {
+    fn splice(&mut self, text: &str, path: &Path) -> Result<(), String> {
+        self.origins
+            .push((self.row_count + 1, path.to_path_buf(), 1));
+        for (i, line) in text.split_inclusive('\n').enumerate() {
+            let relative = match directive(line, self.comment_marker) {
+                Some(relative) => relative,
+                None => {
+                    self.push(line);
+                    continue;
+                }
+            };
+            let fail = |message: String| {
+                let err: ParseError = (i + 1, line, Cow::Owned(message)).into();
+                err.with_filename(path.to_string_lossy()).to_string()
+            };
+            if relative.is_empty() {
+                return Err(fail(["'", API_INCLUDE, "' needs a path"].join("")));
+            }
+
+            let included = path
+                .parent()
+                .unwrap_or_else(|| Path::new(""))
+                .join(relative);
+            let key = canonical(&included);
+            if let Some(start) = self.stack.iter().position(|p| *p == key) {
+                let mut cycle = self.stack[start..]
+                    .iter()
+                    .map(|p| p.to_string_lossy().escape())
+                    .collect::<Vec<_>>();
+                cycle.push(key.to_string_lossy().escape());
+                return Err(fail(["Include cycle: ", &cycle.join(" -> ")].join("")));
+            }
+            let (contents, modified) = fs::read_to_string(&included)
+                .and_then(|contents| Ok((contents, fs::metadata(&included)?)))
+                .map_err(|err| {
+                    fail(
+                        [
+                            "Cannot read ",
+                            included.to_string_lossy().escape().as_str(),
+                            ". ",
+                            err.to_string().as_str(),
+                        ]
+                        .join(""),
+                    )
+                })?;
+            let modified = FileTime::from_last_modification_time(&modified).unix_seconds();
+            self.updated = std::cmp::max(self.updated, Utc.timestamp_opt(modified, 0).unwrap());
+
+            // The scope markers are attributed to the directive
+            self.origins
+                .push((self.row_count + 1, path.to_path_buf(), i + 1));
+            self.push(&[self.comment_marker, API_BEGIN_LANGUAGE, "\n"].join(""));
+            self.stack.push(key);
+            self.splice(&contents, &included)?;
+            self.stack.pop();
+            if !self.buffer.ends_with('\n') {
+                self.push("\n"); // So the end marker is a line of its own
+            }
+            self.origins
+                .push((self.row_count + 1, path.to_path_buf(), i + 1));
+            self.push(&[self.comment_marker, API_END_LANGUAGE, "\n"].join(""));
+            self.origins
+                .push((self.row_count + 1, path.to_path_buf(), i + 2));
+        }
+        Ok(())
+    }
+
+    fn push(&mut self, text: &str) {
+        self.buffer.push_str(text);
+        self.row_count += text.matches('\n').count();
+    }
+}
+
+// e.g. '// api_include: snippet.adoc' with "//" as the comment marker
+fn directive<'a>(line: &'a str, comment_marker: &str) -> Option<&'a str> {
+    line.strip_prefix(comment_marker)
+        .and_then(|rest| rest.trim_start().strip_prefix(API_INCLUDE))
+        .map(str::trim)
+}
+
+// Falls back to the path as is, so a missing file errors when it is read
+fn canonical(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::helpers::TempDir;
+    use crate::post::{LangGroups, Post};
+
+    #[test]
+    fn splice_test() {
+        let temp_dir = TempDir::new("include");
+        let dir = &temp_dir.0;
+        fs::create_dir_all(dir.join("snippets")).unwrap();
+        let post = dir.join("post.adoc");
+        fs::write(
+            dir.join("snippets/a.adoc"),
+            "//api_set_lang: jp\nA\n// api_include: b.adoc\n",
+        )
+        .unwrap();
+        fs::write(dir.join("snippets/b.adoc"), "B").unwrap();
+
+        let text = "//api_set_lang: en jp\nstart\n// api_include: snippets/a.adoc\nend\n";
+        let (spliced, _, origins) = splice(text, "//", &post).unwrap().unwrap();
+        assert_eq!(
+            spliced,
+            "//api_set_lang: en jp\nstart\n//api_begin_lang:\n//api_set_lang: jp\nA\n\
+            //api_begin_lang:\nB\n//api_end_lang:\n//api_end_lang:\nend\n",
+        );
+        let locate = |row| {
+            let (path, row) = origins.locate(row, &post);
+            (path.file_name().unwrap().to_string_lossy().to_string(), row)
+        };
+        assert_eq!(locate(2), ("post.adoc".to_string(), 2));
+        assert_eq!(locate(3), ("post.adoc".to_string(), 3));
+        assert_eq!(locate(5), ("a.adoc".to_string(), 2));
+        assert_eq!(locate(6), ("a.adoc".to_string(), 3));
+        assert_eq!(locate(7), ("b.adoc".to_string(), 1));
+        assert_eq!(locate(9), ("post.adoc".to_string(), 3));
+        assert_eq!(locate(10), ("post.adoc".to_string(), 4));
+
+        // The language set in the included file ends with it
+        let parsed = Post::new(&spliced, "//", None, &LangGroups::default()).unwrap();
+        assert_eq!(parsed.views[0].body.concat(), "start\nend\n");
+        assert_eq!(parsed.views[1].body.concat(), "start\nA\nB\nend\n");
+        assert!(splice("// api_included\n", "//", &post).unwrap().is_none());
+
+        fs::write(dir.join("snippets/b.adoc"), "//api_include: a.adoc\n").unwrap();
+        let err = splice(text, "//", &post).unwrap_err();
+        assert!(err.contains("Include cycle: "), "{}", err);
+        assert!(err.contains("b.adoc:1"), "{}", err);
+    }
+}
@@ -14,16 +14,26 @@ use std::{
     str::Chars,
 };
 
+mod check_api;
 mod compile;
 mod custom_errors;
 mod fileapi;
 mod frontmatter;
 mod helpers;
+mod highlight;
+mod include;
+#[cfg(feature = "markdown")]
+mod markdown;
 mod post;
+mod related;
+mod store;
+mod text;
+mod timings;
 mod traits;
+mod translation;
 mod webserver;
 
-use helpers::{program_name, PathReadMetadata};
+use helpers::{parse_num_option, parse_seconds_option, program_name, PathReadMetadata};
 use traits::{ResultExt, ShellEscape, VecExt};
 
 macro_rules! match_subcommands {
@@ -64,6 +74,9 @@ macro_rules! define_config {
             $($o_short:literal $o_long:literal
                $o_id:ident: $o_type:ty = $o_default:expr => $to_set:expr,)*
         }
+        @optional_with_arg {
+            $($v_short:literal $v_long:literal $v_id:ident,)*
+        }
         @to_be_required {
             $($r_short:literal $r_long:literal $r_id:ident,)*
         }
@@ -74,12 +87,14 @@ macro_rules! define_config {
         #[derive(Debug)]
         pub struct Config {
             $($o_id: $o_type,)*
+            $($v_id: Option<String>,)*
             $($r_id: Option<String>,)*
         }
         impl Config {
             fn new() -> Self {
                 Self {
                     $($o_id: $o_default,)*
+                    $($v_id: None,)*
                     $($r_id: None,)*
                 }
             }
@@ -90,6 +105,7 @@ macro_rules! define_config {
             match option {
                 "h" | "help" => {}
                 $($o_short | $o_long => config.$o_id = $to_set,)*
+                $($v_short | $v_long => config.$v_id = arg_iter.next(),)*
                 $($r_short | $r_long => config.$r_id = arg_iter.next(),)*
                 _ => {
                     return Err([
@@ -119,6 +135,7 @@ macro_rules! define_config {
         #[derive(Debug, Clone)]
         pub struct RequiredConfigs<'a> {
             $($o_id: $o_type,)*
+            $($v_id: Option<&'a str>,)*
             $($r_id: &'a str,)*
             $($d_id: String,)*
         }
@@ -126,6 +143,7 @@ macro_rules! define_config {
             fn unwrap(config: &'a Config) -> Self {
                 let mut output = Self {
                     $($o_id: config.$o_id,)*
+                    $($v_id: config.$v_id.as_deref(),)*
                     $($r_id: config.$r_id.as_ref()
                         .ok_or(MSG.$r_id)
                         .or_die(1)
@@ -136,6 +154,9 @@ macro_rules! define_config {
                 if output.explicit {
                     output.verbose = true;
                 }
+                if output.timings_csv {
+                    output.timings = true;
+                }
                 output
             }
 
@@ -153,6 +174,33 @@ define_config! {
         "v" "verbose"  verbose:  bool = false => true, // true if -v set
         "f" "force"    force:    bool = false => true,
         "e" "explicit" explicit: bool = false => true, // explicit sets verbose
+        // Send the linker a JSON document on STDIN instead of argv 'key:value'
+        "s" "linker-stdin" linker_stdin: bool = false => true,
+        "T" "timings"      timings:      bool = false => true, // report per stage
+        "C" "timings-csv"  timings_csv:  bool = false => true, // implies --timings
+        // Per language JSON in '<public_dir>/<blog_relative>/search/'
+        // Only changed posts are re-tokenised, so use --force on first use
+        "S" "search-index" search_index: bool = false => true,
+        // Stub views (linked with 'fallback_for') for missing --site-langs
+        "F" "fallback-stubs" fallback_stubs: bool = false => true,
+        // Reuse handler outputs by input hash in '<cache_dir>/store/'
+        "X" "output-store" output_store: bool = false => true,
+        // Highlight '<pre><code class="language-X">' (see `highlight-css`)
+        "P" "highlight"    highlight:    bool = false => true,
+    }
+    @optional_with_arg {
+        // short long ident, these are 'None' if not specified
+        "L" "default-lang"          default_lang,          // for posts without langs
+        "O" "default-output-format" default_output_format, // for default lang views
+        "R" "related-count"         related_count,         // related posts per view
+        "G" "site-langs"            site_langs,            // e.g. "en jp zh"
+        // Seconds (e.g. "2.5") before the process group is killed
+        "H" "handler-timeout"       handler_timeout,       // per handler call
+        "K" "linker-timeout"        linker_timeout,        // per linker call
+        "M" "max-output"            max_output,            // bytes of STDOUT/STDERR
+        "Y" "output-store-size"     output_store_size,     // bytes, LRU eviction
+        "A" "handler-map"           handler_map,           // file, see 'fileapi.rs'
+        "N" "lang-groups"           lang_groups,           // e.g. "zh=zh-Hans zh-Hant"
     }
     @to_be_required {
         "a" "api-dir" api_dir,
@@ -170,6 +218,10 @@ define_config! {
         link_cache   = [cache_dir, "/link.csv"],
         changelog    = [cache_dir, "/changelog.csv"],
         series_cache = [cache_dir, "/series.csv"],
+        timings_cache = [cache_dir, "/timings.csv"],
+        search_cache  = [cache_dir, "/search.csv"],
+        excerpt_cache = [cache_dir, "/excerpt.csv"],
+        store_dir     = [cache_dir, "/store"],
     }
 }
 
@@ -242,6 +294,65 @@ fn main() {
 
         }
 
+        2, "translation-report" => {
+            let published_dir = args.get(1).unwrap();
+            let api_dir = config.api_dir.as_ref().ok_or(MSG.api_dir).or_die(1);
+            let input_owner = shallow_walk(published_dir, config.verbose).or_die(1);
+            let mut input_list = Vec::with_capacity(input_owner.len());
+            for (pathbuf, metadata) in &input_owner {
+                let path_obj = PathReadMetadata::wrap_with_metadata(pathbuf.as_path(), metadata).or_die(1);
+                input_list.push_and_check(path_obj);
+            }
+
+            print!("{}", translation::report(
+                api_dir,
+                config.handler_map.as_deref(),
+                config.default_lang.as_deref(),
+                config.lang_groups.as_deref(),
+                config.site_langs.as_deref(),
+                input_list.as_slice(),
+            ));
+        }
+
+        2, "check-api" => {
+            let extension = args.get(1).unwrap();
+            let api_dir = config.api_dir.as_ref().ok_or(MSG.api_dir).or_die(1);
+            let handler_map = fileapi::HandlerMap::read(config.handler_map.as_deref()).or_die(1);
+            let limits = fileapi::Limits {
+                timeout: parse_seconds_option(config.handler_timeout.as_deref(), "handler-timeout")
+                    .or_die(1),
+                max_output: parse_num_option(config.max_output.as_deref(), "max-output").or_die(1),
+            };
+            let temp_dir = env::temp_dir().join(format!("polygot-check-api-{}", std::process::id()));
+            let handler = handler_map.resolve(extension.trim_start_matches('.'));
+            let (report, failed) = check_api::check(api_dir, &handler, limits, &temp_dir).or_die(1);
+            print!("{}", report);
+            exit(if failed > 0 { 1 } else { 0 })
+        }
+
+        1, "highlight-css" => {
+            print!("{}", highlight::CSS);
+        }
+
+        2, "cache" => {
+            let cache_dir = config.cache_dir.as_ref().ok_or(MSG.cache_dir).or_die(1);
+            match args.get(1).unwrap().as_str() {
+                "prune" => {
+                    let store_dir = [cache_dir, "/store"].join("");
+                    let max_size = config.output_store_size.as_deref();
+                    let max_size = parse_num_option(max_size, "output-store-size")
+                        .or_die(1)
+                        .unwrap_or(store::DEFAULT_MAX_SIZE);
+                    let (count, freed) = store::Store::new(&store_dir).prune(max_size).or_die(1);
+                    eprintln!("Pruned {} files ({} bytes) from {}", count, freed, store_dir.escape());
+                }
+                arg => {
+                    eprintln!("`{} cache {}` is an invalid subcommand.", program_name(), arg);
+                    exit(1)
+                }
+            }
+        }
+
         2, "start-server" => {
             let port_string = args.get(1).unwrap();
             let root_loc = config.public_dir
@@ -342,8 +453,25 @@ impl Config {
                 }
             }
         }
+        config.validate_langs()?;
         Ok((config, output))
     }
+
+    // Checked up front as a typo would otherwise go unnoticed, e.g. 'en_US'
+    // in '--site-langs' gives every post a fallback stub for it
+    fn validate_langs(&self) -> Result<(), String> {
+        if let Some(lang) = &self.default_lang {
+            post::validate_lang(lang).map_err(|err| ["--default-lang ", &err].join(""))?;
+        }
+        for lang in self
+            .site_langs
+            .iter()
+            .flat_map(|langs| langs.split_whitespace())
+        {
+            post::validate_lang(lang).map_err(|err| ["--site-langs ", &err].join(""))?;
+        }
+        Ok(())
+    }
 }
 
 enum OptionsSplitState {
@@ -433,20 +561,36 @@ fn shallow_walk(
 
 #[cfg(test)]
 mod integration_tests {
-    use crate::fileapi::FileApi;
+    use crate::fileapi::{Context, FileApi};
     use crate::frontmatter::Frontmatter;
-    use crate::post::Post;
+    use crate::post::{LangGroups, Post};
     use crate::traits::ResultExt;
     use chrono::Utc;
     #[test]
     fn compile_test() {
-        let post = Post::new("hello", "//").or_die(1);
+        let post = Post::new("hello", "//", None, &LangGroups::default()).or_die(1);
         let view = post.views.first().unwrap();
         let api = FileApi::from_filename("config/api/", "adoc").or_die(1);
-        let frontmatter_string = api.frontmatter(view.body.as_slice()).unwrap();
+        let frontmatter_string = api
+            .frontmatter(view.body.as_slice(), &Context::default())
+            .unwrap();
         let frontmatter =
             Frontmatter::new(frontmatter_string.as_str(), Utc::now(), Utc::now()).or_die(1);
         assert!(frontmatter.lookup("date-created").is_some());
         assert!(frontmatter.lookup("date-updated").is_some());
     }
+
+    #[test]
+    fn lang_options_test() {
+        let mut config = crate::Config::new();
+        config.default_lang = Some("en-US".to_string());
+        config.site_langs = Some("en jp zh-Hant".to_string());
+        assert!(config.validate_langs().is_ok());
+        config.site_langs = Some("en en_US".to_string());
+        let err = config.validate_langs().unwrap_err();
+        assert!(err.starts_with("--site-langs 'en_US'"), "{}", err);
+        config.default_lang = Some("en jp".to_string());
+        let err = config.validate_langs().unwrap_err();
+        assert!(err.starts_with("--default-lang "), "{}", err);
+    }
 }
@@ -0,0 +1,249 @@
+// Built-in handler for 'md' so that Markdown posts need no external script
+// Only used if there is no executable for 'md' in the api dir (see 'FileApi')
+//
+// - Comments (and so language markers) are lines starting with 'COMMENT'
+//   outside of code fences, e.g. '// api_set_lang: en'
+// - Frontmatter is a 'key: value' block between '---' lines at the start of
+//   any section (the text between language markers)
+// - The table of contents is built from the headings
+
+use crate::text::escape_html;
+use crate::traits::ShellEscape;
+use pulldown_cmark::{html, Event, HeadingLevel, Options, Parser, Tag};
+use std::fs;
+
+pub const COMMENT: &str = "//";
+const FRONTMATTER_FENCE: &str = "---";
+
+pub fn frontmatter(sections: &[&str]) -> String {
+    let mut output = String::new();
+    for section in sections {
+        if let Some((block, _)) = split_frontmatter(section) {
+            for line in block.lines() {
+                if let Some(colon) = line.find(':') {
+                    output.push_str(line[..colon].trim());
+                    output.push(':');
+                    output.push_str(line[colon + ':'.len_utf8()..].trim());
+                    output.push('\n');
+                }
+            }
+        }
+    }
+    output
+}
+
+pub fn compile(
+    sections: &[&str],
+    toc_location: &str,
+    body_location: &str,
+) -> Result<String, String> {
+    let mut source = String::new();
+    for section in sections {
+        let body = split_frontmatter(section)
+            .map(|(_, body)| body)
+            .unwrap_or(section);
+        push_without_comments(&mut source, body);
+    }
+    let (toc, doc) = to_html(source.as_str());
+    write(toc_location, toc.as_str())?;
+    write(body_location, doc.as_str())?;
+    Ok(String::new())
+}
+
+fn write(location: &str, contents: &str) -> Result<(), String> {
+    fs::write(location, contents).map_err(|err| {
+        [
+            "Cannot write to file ",
+            location.escape().as_str(),
+            ". ",
+            err.to_string().as_str(),
+        ]
+        .join("")
+    })
+}
+
+// Returns (frontmatter, rest of section) if 'section' starts with a block
+fn split_frontmatter(section: &str) -> Option<(&str, &str)> {
+    let trimmed = section.trim_start_matches(['\n', '\r']);
+    let first_line_end = trimmed.find('\n')?;
+    if trimmed[..first_line_end].trim_end() != FRONTMATTER_FENCE {
+        return None;
+    }
+    let block_start = first_line_end + '\n'.len_utf8();
+    let mut cursor = block_start;
+    for line in trimmed[block_start..].split_inclusive('\n') {
+        if line.trim_end() == FRONTMATTER_FENCE {
+            return Some((
+                &trimmed[block_start..cursor],
+                &trimmed[cursor + line.len()..],
+            ));
+        }
+        cursor += line.len();
+    }
+    None
+}
+
+fn push_without_comments(buffer: &mut String, text: &str) {
+    let mut fence: Option<&str> = None;
+    for line in text.split_inclusive('\n') {
+        let trimmed = line.trim_start();
+        match fence {
+            Some(marker) if trimmed.starts_with(marker) => fence = None,
+            Some(_) => {}
+            None if trimmed.starts_with("```") => fence = Some("```"),
+            None if trimmed.starts_with("~~~") => fence = Some("~~~"),
+            None if line.starts_with(COMMENT) => continue,
+            None => {}
+        }
+        buffer.push_str(line);
+    }
+}
+
+// Returns (toc, doc)
+fn to_html(source: &str) -> (String, String) {
+    let mut options = Options::empty();
+    options.insert(Options::ENABLE_TABLES);
+    options.insert(Options::ENABLE_FOOTNOTES);
+    options.insert(Options::ENABLE_STRIKETHROUGH);
+    options.insert(Options::ENABLE_HEADING_ATTRIBUTES);
+    let events = Parser::new_ext(source, options).collect::<Vec<_>>();
+
+    // First pass for the heading ids, (level, id, text)
+    let mut headings = Vec::new();
+    let mut current: Option<(HeadingLevel, Option<&str>, String)> = None;
+    for event in &events {
+        match (event, &mut current) {
+            (Event::Start(Tag::Heading(level, id, _)), _) => {
+                current = Some((*level, *id, String::new()))
+            }
+            (Event::Text(text), Some((_, _, buffer)))
+            | (Event::Code(text), Some((_, _, buffer))) => buffer.push_str(text),
+            (Event::End(Tag::Heading(..)), Some(_)) => {
+                let (level, id, text) = current.take().unwrap();
+                let id = id.map(str::to_string).unwrap_or_else(|| slugify(&text));
+                headings.push((level, unique(&headings, id), text));
+            }
+            _ => {}
+        }
+    }
+
+    let mut index = 0;
+    let events = events.into_iter().map(|event| match event {
+        Event::Start(Tag::Heading(level, _, classes)) => {
+            index += 1;
+            Event::Start(Tag::Heading(
+                level,
+                Some(headings[index - 1].1.as_str()),
+                classes,
+            ))
+        }
+        Event::End(Tag::Heading(level, _, classes)) => Event::End(Tag::Heading(
+            level,
+            Some(headings[index - 1].1.as_str()),
+            classes,
+        )),
+        event => event,
+    });
+    let mut doc = String::with_capacity(source.len() * 3 / 2);
+    html::push_html(&mut doc, events);
+
+    (toc_html(&headings), doc)
+}
+
+// Nested lists like asciidoctor, empty if there are no headings
+fn toc_html(headings: &[(HeadingLevel, String, String)]) -> String {
+    if headings.is_empty() {
+        return String::new();
+    }
+    let mut toc = String::from("<div id=\"toc\" class=\"toc\">\n<ul>\n");
+    let mut depth = vec![headings[0].0 as usize];
+    for (i, (level, id, text)) in headings.iter().enumerate() {
+        let level = *level as usize;
+        if i > 0 {
+            if level > *depth.last().unwrap() {
+                toc.push_str("\n<ul>\n");
+                depth.push(level);
+            } else {
+                toc.push_str("</li>\n");
+                while depth.len() > 1 && level < *depth.last().unwrap() {
+                    toc.push_str("</ul>\n</li>\n");
+                    depth.pop();
+                }
+            }
+        }
+        toc.push_str("<li><a href=\"#");
+        escape_html(&mut toc, id);
+        toc.push_str("\">");
+        escape_html(&mut toc, text);
+        toc.push_str("</a>");
+    }
+    toc.push_str("</li>\n");
+    for _ in 1..depth.len() {
+        toc.push_str("</ul>\n</li>\n");
+    }
+    toc.push_str("</ul>\n</div>\n");
+    toc
+}
+
+fn slugify(text: &str) -> String {
+    let mut slug = String::with_capacity(text.len());
+    for c in text.trim().chars() {
+        if c.is_alphanumeric() || c == '_' || c == '-' {
+            slug.extend(c.to_lowercase());
+        } else if c.is_whitespace() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    if slug.is_empty() {
+        slug.push_str("section");
+    }
+    slug
+}
+
+fn unique(headings: &[(HeadingLevel, String, String)], id: String) -> String {
+    let is_taken = |candidate: &str| headings.iter().any(|(_, taken, _)| taken == candidate);
+    if !is_taken(id.as_str()) {
+        return id;
+    }
+    (2..)
+        .map(|n| [id.as_str(), "-", n.to_string().as_str()].join(""))
+        .find(|candidate| !is_taken(candidate))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn markdown_test() {
+        let sections = [
+            "---\nauthor: Me\ntags: a b\n---\n",
+            "\n---\ntitle: Hello, world\n---\n# Intro\n// a comment\n```\n// kept\n```\n## Intro\n# End {#last}\n",
+        ];
+        assert_eq!(
+            frontmatter(&sections),
+            "author:Me\ntags:a b\ntitle:Hello, world\n"
+        );
+
+        let mut source = String::new();
+        for section in &sections {
+            push_without_comments(&mut source, split_frontmatter(section).unwrap().1);
+        }
+        assert!(!source.contains("a comment"));
+        assert!(source.contains("// kept"));
+
+        let (toc, doc) = to_html(source.as_str());
+        assert!(doc.contains("<h1 id=\"intro\">Intro</h1>"));
+        assert!(doc.contains("<h2 id=\"intro-2\">Intro</h2>"));
+        assert!(doc.contains("<h1 id=\"last\">End</h1>"));
+        assert_eq!(
+            toc,
+            "<div id=\"toc\" class=\"toc\">\n<ul>\n\
+             <li><a href=\"#intro\">Intro</a>\n<ul>\n\
+             <li><a href=\"#intro-2\">Intro</a></li>\n</ul>\n</li>\n\
+             <li><a href=\"#last\">End</a></li>\n</ul>\n</div>\n"
+        );
+        assert_eq!(to_html("No headings").0, "");
+    }
+}
@@ -1,13 +1,19 @@
 use crate::custom_errors::ParseError;
 use crate::helpers::parse_tags_and_push;
-use crate::traits::{BoolExt, VecExt};
+use crate::include::Origins;
+use crate::traits::{BoolExt, ShellEscape, VecExt};
 use std::borrow::Cow;
 
 //run: cargo test -- --nocapture
 
-const API_SET_LANGUAGE: &str = "api_set_lang:";
+pub const API_SET_LANGUAGE: &str = "api_set_lang:";
+pub const API_BEGIN_LANGUAGE: &str = "api_begin_lang:";
+pub const API_END_LANGUAGE: &str = "api_end_lang:";
+const API_EXCERPT: &str = "api_excerpt:";
+const API_LANG_GROUP: &str = "api_lang_group:";
 const ALL_LANG: Option<&str> = None;
-const ALL_LANG_REPR: [&str; 2] = ["*", "ALL"]; // case-sensitive
+pub const ALL_LANG_REPR: [&str; 2] = ["*", "ALL"]; // case-sensitive
+const NEGATION: [char; 2] = ['!', '-']; // e.g. 'api_set_lang: ALL -jp'
 
 // Originally, I wanted 'Post' to own the post and hand out views as borrows
 // https://cfsamson.github.io/books-futures-explained/4_pin.html
@@ -18,53 +24,67 @@ pub struct Post<'a> {
     original: &'a str,
     pub views: Vec<PostView<'a>>,
     pub lang_list: Vec<&'a str>,
+    pub lang_groups: LangGroups<'a>, // Including the ones the post declares
+    pub origins: Origins,            // Set if 'text' had includes spliced in
 }
 
 #[derive(Debug)]
 pub struct PostView<'a> {
     pub lang: Option<&'a str>,
     pub body: Vec<&'a str>,
+    pub rows: Vec<usize>, // Row in the post of the first line of each 'body'
+    pub excerpt: Option<&'a str>, // From the first 'api_excerpt:' comment
 }
 
 impl<'a> Post<'a> {
-    pub fn new(text: &'a str, comment_marker: &str) -> Result<Self, ParseError<'a>> {
+    // 'default_lang' is the language given to posts without 'api_set_lang'
+    // 'groups' are from the config, the post can declare more of its own
+    pub fn new(
+        text: &'a str,
+        comment_marker: &str,
+        default_lang: Option<&'a str>,
+        groups: &LangGroups<'a>,
+    ) -> Result<Self, ParseError<'a>> {
+        let groups = groups.with_declared(text, comment_marker)?;
+
         // 'lang_count' will count all duplicates (which is the common case)
         // e.g. api_set_lang: en jp
         //      api_set_lang: ALL
         //      api_set_lang: en
         // counts four
         // It might just be better to let 'unique_langs' auto size
-        let (part_count, lang_max) = SplitByLabel::new(text, comment_marker).fold(
-            (0, 0),
-            |(sections, langs), (_, lang_label, _, _)| {
-                (sections + 1, langs + lang_label.split_whitespace().count())
-            },
-        );
+        let (part_count, lang_max) =
+            SplitByLabel::new(text, comment_marker).fold((0, 0), |(sections, langs), item| {
+                match item {
+                    Ok((_, lang_label, _, _)) => {
+                        (sections + 1, langs + lang_label.split_whitespace().count())
+                    }
+                    Err(_) => (sections, langs),
+                }
+            });
 
         let mut unique_langs = Vec::with_capacity(lang_max);
         let mut parts = Vec::with_capacity(part_count);
+        let mut rows = Vec::with_capacity(part_count);
         let mut labels = Vec::with_capacity(part_count);
         {
-            let mut cur_langs = ALL_LANG_REPR[0]; // start with an all label
-            for (section, next_langs, line, row) in SplitByLabel::new(text, comment_marker) {
-                let has_all_label = cur_langs
-                    .split_whitespace()
-                    .any(|t| ALL_LANG_REPR.contains(&t));
+            // Start with an all label, 'line' and 'row' are where it was set
+            let (mut cur_langs, mut cur_line, mut cur_row) = (ALL_LANG_REPR[0], "", 1);
+            for item in SplitByLabel::new(text, comment_marker) {
+                let (section, next_langs, line, row) = item?;
 
                 // Build up 'unique_langs' list
                 // e.g. 'api_set_lang: en ALL jp' -> 'en all jp'
                 // May be both all and list languages
-                let langs =
-                    parse_tags_and_push(&mut unique_langs, cur_langs, &ALL_LANG_REPR, false)
-                        .map_err(|err| (row, line, Cow::Owned(err)))?;
-
-                //print!("{:?} | {:?}", cur_langs, langs);
-                let label = (!has_all_label && !langs.is_empty()).to_some(langs);
+                let label = Label::parse(cur_langs, &mut unique_langs, &groups)
+                    .map_err(|err| (cur_row, cur_line, Cow::Owned(err)))?;
                 //println!(" {:?} | {:?}", label, section);
 
                 labels.push_and_check(label);
                 parts.push_and_check(section);
-                cur_langs = next_langs;
+                let offset = section.as_ptr() as usize - text.as_ptr() as usize;
+                rows.push_and_check(text[..offset].matches('\n').count() + 1);
+                (cur_langs, cur_line, cur_row) = (next_langs, line, row);
 
                 //println!("{:?}", replace(&mut label, Some(langs)));
                 //println!("{:?}", has_all_label);
@@ -78,29 +98,34 @@ impl<'a> Post<'a> {
         //println!();
 
         unique_langs.sort_unstable();
+        if unique_langs.is_empty() {
+            if let Some(lang) = default_lang {
+                unique_langs.push(lang);
+            }
+        }
 
         // Transpose 'parts' from by parts-by-langs to langs-by-parts (PostView)
         let lang_count = unique_langs.len(); // bet
         let mut view_list = Vec::with_capacity(std::cmp::max(lang_count, 1));
-        if unique_langs.is_empty() {
+        if lang_count == 0 {
             view_list.push_and_check(PostView {
                 lang: None,
+                excerpt: find_excerpt(&parts, comment_marker),
                 body: parts,
+                rows,
             });
         } else {
             for lang in &unique_langs {
                 let mut view = Vec::with_capacity(part_count);
+                let mut view_rows = Vec::with_capacity(part_count);
 
-                let mut iter = labels.iter();
-                view.extend(parts.iter().filter(|_| {
-                    if let Some(Some(lang_labels)) = iter.next() {
-                        //println!("{:?}", lang_labels);
-                        //false
-                        lang_labels.iter().any(|l| l == lang)
-                    } else {
-                        true
+                // Exclusions are resolved here, against every language
+                for ((part, row), label) in parts.iter().zip(&rows).zip(&labels) {
+                    if label.includes(lang) {
+                        view.push_and_check(*part);
+                        view_rows.push_and_check(*row);
                     }
-                }));
+                }
                 //view.extend(
                 //    parts
                 //        .iter()
@@ -109,7 +134,9 @@ impl<'a> Post<'a> {
 
                 view_list.push_and_check(PostView {
                     lang: Some(lang),
+                    excerpt: find_excerpt(&view, comment_marker),
                     body: view,
+                    rows: view_rows,
                 });
             }
         }
@@ -118,32 +145,247 @@ impl<'a> Post<'a> {
             original: text,
             views: view_list,
             lang_list: unique_langs,
+            lang_groups: groups,
+            origins: Origins::default(),
         })
     }
 }
 
+impl<'a> PostView<'a> {
+    // The row in the post of 'view_row' in the joined 'body' (both from 1)
+    pub fn source_row(&self, view_row: usize) -> Option<usize> {
+        let mut first = 1;
+        for (section, row) in self.body.iter().zip(&self.rows) {
+            let mut count = section.matches('\n').count();
+            count += (!section.is_empty() && !section.ends_with('\n')) as usize;
+            if (first..first + count).contains(&view_row) {
+                return Some(row + view_row - first);
+            }
+            first += count;
+        }
+        None
+    }
+}
+
+// The languages of an 'api_set_lang' label
+// e.g. 'en jp' lists, 'ALL' or '*' is every language, and '!jp' or 'ALL -jp'
+// is every language but 'jp'. Groups expand to their languages
+#[derive(Debug, Default)]
+pub struct Label<'a> {
+    pub langs: Option<Vec<&'a str>>, // None for every language
+    pub excluded: Vec<&'a str>,
+}
+
+impl<'a> Label<'a> {
+    // Listed languages are added to 'unique_langs', excluded ones are not
+    pub fn parse(
+        label: &'a str,
+        unique_langs: &mut Vec<&'a str>,
+        groups: &LangGroups<'a>,
+    ) -> Result<Self, String> {
+        let mut has_all_label = false;
+        let mut langs = Vec::new();
+        let mut excluded = Vec::new();
+        for tag in label.split_whitespace() {
+            if let Some(lang) = tag.strip_prefix(&NEGATION[..]) {
+                if lang.is_empty() || ALL_LANG_REPR.contains(&lang) {
+                    return Err([
+                        tag.escape().as_str(),
+                        " does not name a language to exclude",
+                    ]
+                    .join(""));
+                }
+                for lang in groups.expand(lang) {
+                    validate_lang(lang)?;
+                    excluded.extend(parse_tags_and_push(&mut Vec::new(), lang, &[], false)?);
+                }
+            } else if ALL_LANG_REPR.contains(&tag) {
+                has_all_label = true;
+            } else {
+                for lang in groups.expand(tag) {
+                    validate_lang(lang)?;
+                    langs.extend(parse_tags_and_push(unique_langs, lang, &[], false)?);
+                }
+            }
+        }
+        // Only exclusions, e.g. '!jp', means every other language
+        has_all_label |= langs.is_empty() && !excluded.is_empty();
+        Ok(Self {
+            langs: (!has_all_label && !langs.is_empty()).to_some(langs),
+            excluded,
+        })
+    }
+
+    pub fn includes(&self, lang: &str) -> bool {
+        let is_listed = match &self.langs {
+            Some(langs) => langs.contains(&lang),
+            None => true,
+        };
+        is_listed && !self.excluded.contains(&lang)
+    }
+
+    pub fn is_all(&self) -> bool {
+        self.langs.is_none() && self.excluded.is_empty()
+    }
+}
+
+// Names for several languages in labels, e.g. 'zh' for 'zh-Hans zh-Hant'
+// From '--lang-groups' or declared in a post, e.g.
+//   // api_lang_group: zh=zh-Hans zh-Hant
+// Only the languages get views, not the group names
+#[derive(Clone, Debug, Default)]
+pub struct LangGroups<'a>(Vec<(&'a str, Vec<&'a str>)>);
+
+impl<'a> LangGroups<'a> {
+    // e.g. "zh=zh-Hans zh-Hant,pt=pt-BR pt-PT"
+    pub fn parse(groups: Option<&'a str>) -> Result<Self, String> {
+        let mut list = Self::default();
+        for definition in groups.unwrap_or("").split(',') {
+            if !definition.trim().is_empty() {
+                list.add(definition)
+                    .map_err(|err| ["--lang-groups ", &err].join(""))?;
+            }
+        }
+        Ok(list)
+    }
+
+    // A copy with the 'api_lang_group:' lines of 'text' added
+    fn with_declared(&self, text: &'a str, comment_marker: &str) -> Result<Self, ParseError<'a>> {
+        let mut list = self.clone();
+        let mut declared = Vec::new(); // Only the post's own groups can clash
+        for (i, line) in text.lines().enumerate() {
+            let definition = line
+                .strip_prefix(comment_marker)
+                .and_then(|rest| rest.trim_start().strip_prefix(API_LANG_GROUP));
+            if let Some(definition) = definition {
+                let name = list
+                    .add(definition)
+                    .and_then(|name| match declared.contains(&name) {
+                        true => Err([name.escape().as_str(), " is already declared"].join("")),
+                        false => Ok(name),
+                    });
+                declared.push(name.map_err(|err| (i + 1, line, Cow::Owned(err)))?);
+            }
+        }
+        Ok(list)
+    }
+
+    // e.g. "zh=zh-Hans zh-Hant", replaces any group of the same name
+    fn add(&mut self, definition: &'a str) -> Result<&'a str, String> {
+        let (name, langs) = definition.split_once('=').ok_or_else(|| {
+            [
+                definition.trim().escape().as_str(),
+                " is not a group, e.g. 'zh=zh-Hans zh-Hant'",
+            ]
+            .join("")
+        })?;
+        let name = name.trim();
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return Err([name.escape().as_str(), " is not a valid group name"].join(""));
+        } else if ALL_LANG_REPR.contains(&name) || name.starts_with(&NEGATION[..]) {
+            return Err([name.escape().as_str(), " cannot be a group name"].join(""));
+        }
+        parse_tags_and_push(&mut Vec::new(), name, &[], false)?;
+        let langs = parse_tags_and_push(&mut Vec::new(), langs, &[], true)?;
+        if langs.is_empty() {
+            return Err(["The group ", name.escape().as_str(), " has no languages"].join(""));
+        }
+        langs.iter().try_for_each(|lang| validate_lang(lang))?;
+
+        self.0.retain(|(group, _)| *group != name);
+        self.0.push((name, langs));
+        Ok(name)
+    }
+
+    // Groups are not nested, so the languages are never group names
+    fn expand(&self, tag: &'a str) -> Vec<&'a str> {
+        match self.0.iter().find(|(name, _)| *name == tag) {
+            Some((_, langs)) => langs.clone(),
+            None => vec![tag],
+        }
+    }
+}
+
+// Syntax only, as in BCP 47 (RFC 5646), not checked against the registry
+// e.g. 'en', 'en-US', 'zh-Hant', 'es-419', 'sr-Latn-RS'
+// Catches e.g. 'en_US' making an extra view next to 'en-US'
+pub fn is_bcp47(tag: &str) -> bool {
+    let mut subtags = tag.split('-');
+    let primary = subtags.next().unwrap_or("");
+    let is_primary = match primary.len() {
+        1 => primary.eq_ignore_ascii_case("x"), // Private use, e.g. 'x-klingon'
+        2..=3 | 5..=8 => primary.bytes().all(|b| b.is_ascii_alphabetic()),
+        _ => false,
+    };
+
+    // A singleton (e.g. 'u' in 'de-DE-u-co-phonebk') needs a subtag after it
+    let mut is_singleton_open = primary.len() == 1;
+    for subtag in subtags {
+        let is_valid =
+            (1..=8).contains(&subtag.len()) && subtag.bytes().all(|b| b.is_ascii_alphanumeric());
+        if !is_valid || (is_singleton_open && subtag.len() == 1) {
+            return false;
+        }
+        is_singleton_open = subtag.len() == 1;
+    }
+    is_primary && !is_singleton_open
+}
+
+pub fn validate_lang(lang: &str) -> Result<(), String> {
+    if is_bcp47(lang) {
+        return Ok(());
+    }
+    let mut err = [lang.escape().as_str(), " is not a BCP 47 language tag"].join("");
+    let suggestion = lang.replace('_', "-");
+    if suggestion != lang && is_bcp47(&suggestion) {
+        err.push_str(
+            [", did you mean ", suggestion.escape().as_str(), "?"]
+                .join("")
+                .as_str(),
+        );
+    }
+    Err(err)
+}
+
 /******************************************************************************
  * Post helper functions
  ******************************************************************************/
-struct SplitByLabel<'a, 'b> {
+// e.g. '// api_excerpt: A short summary' with "//" as the comment marker
+fn find_excerpt<'a>(body: &[&'a str], comment_marker: &str) -> Option<&'a str> {
+    body.iter()
+        .flat_map(|section| section.lines())
+        .filter_map(|line| line.strip_prefix(comment_marker))
+        .find_map(|line| line.trim_start().strip_prefix(API_EXCERPT))
+        .map(str::trim)
+}
+
+// Yields (section, languages of the next section, marker line, marker row)
+// Begin and end markers scope languages like a stack, so the end marker
+// yields the languages (and the line they were set on) of the enclosing
+// section. Unbalanced markers are errors.
+pub struct SplitByLabel<'a, 'b> {
     iter: std::str::Chars<'a>,
     comment: &'b str,
     comment_len: usize,
     row: usize,
+    cur: (&'a str, &'a str, usize),
+    scopes: Vec<((&'a str, &'a str, usize), &'a str, usize)>, // Enclosing, begin
 }
 impl<'a, 'b> SplitByLabel<'a, 'b> {
-    fn new(buffer: &'a str, comment: &'b str) -> Self {
+    pub fn new(buffer: &'a str, comment: &'b str) -> Self {
         Self {
             iter: buffer.chars(),
             comment,
             comment_len: comment.len(),
             row: 0,
+            cur: (ALL_LANG_REPR[0], "", 1),
+            scopes: Vec::new(),
         }
     }
 }
 
 impl<'a, 'b> Iterator for SplitByLabel<'a, 'b> {
-    type Item = (&'a str, &'a str, &'a str, usize);
+    type Item = Result<(&'a str, &'a str, &'a str, usize), ParseError<'a>>;
     fn next(&mut self) -> Option<Self::Item> {
         enum State {
             Blank,
@@ -172,26 +414,48 @@ impl<'a, 'b> Iterator for SplitByLabel<'a, 'b> {
                 }
                 (State::Slash(_), _) if c.is_whitespace() => {}
                 (State::Slash(mid), _) => {
-                    //let right = &as_str[*mid..];
-                    //let end = right.find('\n').unwrap_or(right.len());
-                    //return Some((&as_str[..*mid], &right[..end]));
-                    //println!("- {:?}\n", &as_str[*mid..]);
-
-                    if rest.starts_with(API_SET_LANGUAGE) {
+                    let marker = [API_SET_LANGUAGE, API_BEGIN_LANGUAGE, API_END_LANGUAGE]
+                        .iter()
+                        .find(|marker| rest.starts_with(*marker));
+                    if let Some(marker) = marker {
                         let left = &as_str[..*mid];
+                        let row = self.row + 1;
 
                         // Skip until newline
                         let mut end = cursor + c.len_utf8();
                         while let Some(r) = self.iter.next() {
                             end += r.len_utf8();
                             if r == '\n' {
+                                self.row += 1;
                                 break;
                             }
                         }
-                        let right = &as_str[cursor + API_SET_LANGUAGE.len()..end];
+                        let right = &as_str[cursor + marker.len()..end];
                         let label = &as_str[*mid..end];
 
-                        return Some((left, right, label, self.row));
+                        match *marker {
+                            API_BEGIN_LANGUAGE => {
+                                self.scopes.push((self.cur, label, row));
+                                // Without languages, the enclosing ones stay
+                                if !right.trim().is_empty() {
+                                    self.cur = (right, label, row);
+                                }
+                            }
+                            API_END_LANGUAGE if !right.trim().is_empty() => {
+                                let msg = ["'", API_END_LANGUAGE, "' takes no languages"];
+                                return Some(Err((row, label, msg.join("").into()).into()));
+                            }
+                            API_END_LANGUAGE => match self.scopes.pop() {
+                                Some((enclosing, _, _)) => self.cur = enclosing,
+                                None => {
+                                    let msg = ["No '", API_BEGIN_LANGUAGE, "' to end"];
+                                    return Some(Err((row, label, msg.join("").into()).into()));
+                                }
+                            },
+                            _ => self.cur = (right, label, row),
+                        }
+                        let (langs, label, row) = self.cur;
+                        return Some(Ok((left, langs, label, row)));
                     } else {
                         state = State::Blank;
                     }
@@ -203,7 +467,18 @@ impl<'a, 'b> Iterator for SplitByLabel<'a, 'b> {
             cursor += c.len_utf8();
         }
         if cursor > 0 {
-            Some((&as_str[..cursor], "", "", self.row))
+            Some(Ok((&as_str[..cursor], "", "", self.row + 1)))
+        } else if let Some((_, line, row)) = self.scopes.pop() {
+            // Report the innermost unclosed marker, then stop
+            self.scopes.clear();
+            let msg = [
+                "This '",
+                API_BEGIN_LANGUAGE,
+                "' has no '",
+                API_END_LANGUAGE,
+                "'",
+            ];
+            Some(Err((row, line, msg.join("").into()).into()))
         } else {
             None
         }
@@ -225,10 +500,169 @@ mod test {
 
     fn parse(buffer: &str) -> Vec<(&str, &str)> {
         SplitByLabel::new(buffer, COMMENT)
-            .map(|x| (x.0, x.1))
+            .map(|x| x.map(|x| (x.0, x.1)).unwrap())
             .collect::<Vec<_>>()
     }
 
+    #[test]
+    fn default_lang() {
+        let post = Post::new("no markers\n", COMMENT, Some("en"), &LangGroups::default()).unwrap();
+        assert_eq!(post.lang_list, vec!["en"]);
+        assert_eq!(post.views[0].lang, Some("en"));
+
+        let post = Post::new("no markers\n", COMMENT, None, &LangGroups::default()).unwrap();
+        assert!(post.lang_list.is_empty());
+        assert_eq!(post.views[0].lang, None);
+
+        let text = &format!("{}{} jp\nhello\n", COMMENT, API_SET_LANGUAGE);
+        let post = Post::new(text, COMMENT, Some("en"), &LangGroups::default()).unwrap();
+        assert_eq!(post.lang_list, vec!["jp"]);
+    }
+
+    #[test]
+    fn excerpt_marker() {
+        let text = &format!(
+            "{c}{s} jp\n{c}{e} Japanese only\n{c}{s} en jp\n{c} {e} Both \n",
+            c = COMMENT,
+            s = API_SET_LANGUAGE,
+            e = API_EXCERPT,
+        );
+        let post = Post::new(text, COMMENT, None, &LangGroups::default()).unwrap();
+        assert_eq!(post.views[0].excerpt, Some("Both"));
+        assert_eq!(post.views[1].excerpt, Some("Japanese only"));
+
+        let post = Post::new("no excerpt\n", COMMENT, None, &LangGroups::default()).unwrap();
+        assert_eq!(post.views[0].excerpt, None);
+    }
+
+    #[test]
+    fn scoped_markers() {
+        let text = &format!(
+            "{c}{s} en jp\nboth\n{c}{b} jp\n{c}{b} ALL\nall\n{c}{e}\njp\n{c} {e}\nboth again\n{c}{b}\nstill both\n{c}{e}\nend\n",
+            c = COMMENT,
+            s = API_SET_LANGUAGE,
+            b = API_BEGIN_LANGUAGE,
+            e = API_END_LANGUAGE,
+        );
+        let post = Post::new(text, COMMENT, None, &LangGroups::default()).unwrap();
+        assert_eq!(
+            post.views[0].body,
+            vec![
+                "",
+                "both\n",
+                "all\n",
+                "both again\n",
+                "still both\n",
+                "end\n"
+            ]
+        );
+        assert_eq!(
+            post.views[1].body,
+            vec![
+                "",
+                "both\n",
+                "",
+                "all\n",
+                "jp\n",
+                "both again\n",
+                "still both\n",
+                "end\n"
+            ]
+        );
+
+        let text = &format!("a\n{c}{e}\n", c = COMMENT, e = API_END_LANGUAGE);
+        let err = Post::new(text, COMMENT, None, &LangGroups::default())
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("2 | //api_end_lang:"), "{}", err);
+        let text = &format!(
+            "{c}{b} en\n{c}{b} jp\n{c}{e}\n",
+            c = COMMENT,
+            b = API_BEGIN_LANGUAGE,
+            e = API_END_LANGUAGE
+        );
+        let err = Post::new(text, COMMENT, None, &LangGroups::default())
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("1 | //api_begin_lang: en\n"), "{}", err);
+    }
+
+    #[test]
+    fn negated_labels() {
+        let text = &format!(
+            "{c}{s} en jp zh\nall\n{c}{s} !jp\nnot jp\n{c}{s} ALL -en -zh\njp\n{c}{s} en jp !en\njp too\n",
+            c = COMMENT,
+            s = API_SET_LANGUAGE,
+        );
+        let post = Post::new(text, COMMENT, None, &LangGroups::default()).unwrap();
+        assert_eq!(post.lang_list, vec!["en", "jp", "zh"]);
+        assert_eq!(post.views[0].body, vec!["", "all\n", "not jp\n"]);
+        assert_eq!(post.views[1].body, vec!["", "all\n", "jp\n", "jp too\n"]);
+        assert_eq!(post.views[2].body, vec!["", "all\n", "not jp\n"]);
+
+        let text = &format!("{c}{s} en !\nx\n", c = COMMENT, s = API_SET_LANGUAGE);
+        assert!(Post::new(text, COMMENT, None, &LangGroups::default()).is_err());
+    }
+
+    #[test]
+    fn lang_groups() {
+        let config = LangGroups::parse(Some("zh=zh-Hans zh-Hant, pt=pt-BR")).unwrap();
+        let text = &format!(
+            "{c} {g} pt=pt-BR pt-PT\n{c}{s} en zh\nen zh\n{c}{s} !zh\nnot zh\n{c}{s} pt\npt\n",
+            c = COMMENT,
+            g = API_LANG_GROUP,
+            s = API_SET_LANGUAGE,
+        );
+        let post = Post::new(text, COMMENT, None, &config).unwrap();
+        assert_eq!(
+            post.lang_list,
+            vec!["en", "pt-BR", "pt-PT", "zh-Hans", "zh-Hant"]
+        );
+        assert_eq!(
+            post.views[0].body,
+            vec!["// api_lang_group: pt=pt-BR pt-PT\n", "en zh\n", "not zh\n"]
+        );
+        assert_eq!(
+            post.views[3].body,
+            vec!["// api_lang_group: pt=pt-BR pt-PT\n", "en zh\n"]
+        );
+
+        let text = &format!("{c}{s} en_US\nx\n", c = COMMENT, s = API_SET_LANGUAGE);
+        let err = Post::new(text, COMMENT, None, &config)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("did you mean 'en-US'?"), "{}", err);
+        assert!(LangGroups::parse(Some("zh=zh_Hant")).is_err());
+        assert!([
+            "en",
+            "en-US",
+            "zh-Hant",
+            "es-419",
+            "de-DE-u-co-phonebk",
+            "x-klingon"
+        ]
+        .iter()
+        .all(|tag| is_bcp47(tag)));
+        assert!(!["english-", "e", "en-", "en-u", "toolonglang"]
+            .iter()
+            .any(|tag| is_bcp47(tag)));
+    }
+
+    #[test]
+    fn source_rows() {
+        let text = &format!(
+            "{c}{s} en\nen 1\nen 2\n{c}{s} jp\njp\n{c}{s} ALL\nall\n",
+            c = COMMENT,
+            s = API_SET_LANGUAGE,
+        );
+        let post = Post::new(text, COMMENT, None, &LangGroups::default()).unwrap();
+        let en = &post.views[0];
+        assert_eq!(en.rows, vec![1, 2, 7]);
+        let located = (1..=5).map(|row| en.source_row(row)).collect::<Vec<_>>();
+        assert_eq!(located, vec![Some(2), Some(3), Some(7), None, None]);
+        assert_eq!(post.views[1].source_row(2), Some(7));
+    }
+
     #[test]
     fn split_only_delimiter() {
         let line = &format!("{}{} hello", COMMENT, API_SET_LANGUAGE);
@@ -0,0 +1,106 @@
+// Ranking of "related posts" from the tags and series caches
+// A view is related to other views of the same language by the number of
+// tags and series they share, with more recently created posts winning ties
+
+use std::collections::HashMap;
+
+#[derive(Debug, Default)]
+pub struct LabelledView<'cache> {
+    pub date: &'cache str,
+    pub title: &'cache str,
+    // (is_series, label) so that a tag and a series of the same name differ
+    pub labels: Vec<(bool, &'cache str)>,
+}
+
+// Keyed by (id, lang)
+pub type LabelMap<'cache> = HashMap<(&'cache str, &'cache str), LabelledView<'cache>>;
+
+// @FORMAT tags cache and series cache
+// label,date,id,lang,title (title is the only column with commas as data)
+pub fn parse_label_caches<'cache>(
+    tags_cache: &'cache str,
+    series_cache: &'cache str,
+) -> LabelMap<'cache> {
+    let mut map: LabelMap = HashMap::new();
+    let tags = tags_cache.lines().map(|line| (false, line));
+    let series = series_cache.lines().map(|line| (true, line));
+    for (is_series, line) in tags.chain(series) {
+        let mut iter = line.splitn(5, ',');
+        if let (Some(label), Some(date), Some(id), Some(lang), Some(title)) = (
+            iter.next(),
+            iter.next(),
+            iter.next(),
+            iter.next(),
+            iter.next(),
+        ) {
+            let entry = map.entry((id, lang)).or_default();
+            entry.date = date;
+            entry.title = title;
+            entry.labels.push((is_series, label));
+        }
+    }
+    for entry in map.values_mut() {
+        entry.labels.sort_unstable();
+        entry.labels.dedup();
+    }
+    map
+}
+
+// Returns the ids of the top 'count' related views
+pub fn rank<'cache>(
+    labels: &LabelMap<'cache>,
+    id: &str,
+    lang: &str,
+    count: usize,
+) -> Vec<&'cache str> {
+    let own = match labels.get(&(id, lang)) {
+        Some(entry) => &entry.labels,
+        None => return Vec::new(),
+    };
+
+    let mut scored = labels
+        .iter()
+        .filter(|((other_id, other_lang), _)| *other_lang == lang && *other_id != id)
+        .map(|((other_id, _), entry)| {
+            let shared = entry.labels.iter().filter(|l| own.contains(l)).count();
+            (shared, entry.date, *other_id)
+        })
+        .filter(|(shared, _, _)| *shared > 0)
+        .collect::<Vec<_>>();
+
+    // Most shared labels, then most recent, then by id so output is stable
+    scored.sort_unstable_by(|a, b| b.0.cmp(&a.0).then(b.1.cmp(a.1)).then(a.2.cmp(b.2)));
+    scored
+        .into_iter()
+        .take(count)
+        .map(|(_, _, id)| id)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rank_test() {
+        let tags = "\
+Junk,2020-01-01 00:00:00,a,en,A
+Linguistics,2020-01-01 00:00:00,a,en,A
+Junk,2020-02-01 00:00:00,b,en,B, with comma
+Linguistics,2020-02-01 00:00:00,b,en,B, with comma
+Junk,2020-03-01 00:00:00,c,en,C
+Junk,2020-04-01 00:00:00,d,en,D
+Junk,2020-05-01 00:00:00,e,jp,E
+Unrelated,2020-05-01 00:00:00,f,en,F";
+        let series = "Intro,2020-03-01 00:00:00,c,en,C\nIntro,2020-01-01 00:00:00,a,en,A";
+        let labels = parse_label_caches(tags, series);
+        assert_eq!(labels.get(&("b", "en")).unwrap().title, "B, with comma");
+
+        // 'c' ties 'b' by sharing the series with 'a', and is more recent
+        assert_eq!(rank(&labels, "a", "en", 3), vec!["c", "b", "d"]);
+        assert_eq!(rank(&labels, "a", "en", 1), vec!["c"]);
+        assert_eq!(rank(&labels, "d", "en", 5), vec!["c", "b", "a"]);
+        assert!(rank(&labels, "e", "jp", 5).is_empty());
+        assert!(rank(&labels, "missing", "en", 5).is_empty());
+    }
+}
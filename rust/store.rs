@@ -0,0 +1,223 @@
+// Content-addressed store of handler outputs (with '--output-store')
+// Switching branches or reverting a post recompiles views whose exact input
+// was already compiled before. Outputs are saved in '<cache_dir>/store/' by a
+// hash of everything the handler sees, so those become file copies instead.
+//
+// - '<key>.frontmatter': STDOUT of 'frontmatter'
+// - '<key>.toc' and '<key>.doc': the partials written by 'compile'
+//
+// Hits bump the mtime so eviction (oldest mtime first) is least recently used
+// The hash is SHA-256 as a collision would silently serve another view's outputs
+
+use crate::fileapi::{Context, FileApi};
+use crate::traits::ShellEscape;
+use filetime::FileTime;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const DEFAULT_MAX_SIZE: u64 = 256 * 1024 * 1024; // Bytes
+
+// The handler variables (see 'Context::vars()') that are part of the key
+// Where the post or '--cache-dir' is kept is left out so moving them still hits
+const KEYED_VARS: [&str; 2] = ["POLYGOT_LANG", "POLYGOT_OTHER_LANGS"];
+
+pub struct Store {
+    dir: PathBuf,
+}
+
+impl Store {
+    pub fn new(store_dir: &str) -> Self {
+        Self {
+            dir: PathBuf::from(store_dir),
+        }
+    }
+
+    // Everything that can change the output, separated (by a byte that is
+    // never in UTF-8) so that moving bytes between fields changes the hash
+    pub fn key(api: &FileApi, args: &[&str], context: &Context, body: &[&str]) -> String {
+        let mut hash = Sha256::new();
+        let mut field = |bytes: &[u8]| {
+            hash.update(bytes);
+            hash.update([0xff]);
+        };
+        field(api.identity().as_bytes());
+        args.iter().for_each(|arg| field(arg.as_bytes()));
+        for (key, value) in context.vars() {
+            if KEYED_VARS.contains(&key) {
+                field(key.as_bytes());
+                field(value.as_bytes());
+            }
+        }
+        body.iter().for_each(|section| field(section.as_bytes()));
+        hash.finalize()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    pub fn get_frontmatter(&self, key: &str) -> Option<String> {
+        let path = self.entry(key, "frontmatter");
+        let frontmatter = fs::read_to_string(&path).ok()?;
+        touch(&path);
+        Some(frontmatter)
+    }
+
+    pub fn put_frontmatter(&self, key: &str, frontmatter: &str) -> Result<(), String> {
+        self.create_dir()?;
+        let path = self.entry(key, "frontmatter");
+        fs::write(&path, frontmatter).map_err(|err| write_error(&path, err))
+    }
+
+    // Returns false on a miss, in which case neither partial is written
+    pub fn restore_partials(&self, key: &str, toc_loc: &str, doc_loc: &str) -> bool {
+        let toc = self.entry(key, "toc");
+        let doc = self.entry(key, "doc");
+        if !toc.is_file() || !doc.is_file() {
+            return false;
+        }
+        if fs::copy(&toc, toc_loc).is_err() || fs::copy(&doc, doc_loc).is_err() {
+            return false;
+        }
+        touch(&toc);
+        touch(&doc);
+        true
+    }
+
+    pub fn save_partials(&self, key: &str, toc_loc: &str, doc_loc: &str) -> Result<(), String> {
+        self.create_dir()?;
+        for (extension, loc) in [("toc", toc_loc), ("doc", doc_loc)] {
+            let path = self.entry(key, extension);
+            fs::copy(loc, &path).map_err(|err| write_error(&path, err))?;
+        }
+        Ok(())
+    }
+
+    // Deletes the least recently used entries until the store is at most
+    // 'max_size' bytes. Returns the (count, bytes) deleted
+    pub fn prune(&self, max_size: u64) -> Result<(usize, u64), String> {
+        let read_dir = match fs::read_dir(&self.dir) {
+            Ok(read_dir) => read_dir,
+            Err(_) => return Ok((0, 0)), // Nothing stored yet
+        };
+        let mut entries = read_dir
+            .filter_map(|entry| {
+                let entry = entry.ok()?;
+                let metadata = entry.metadata().ok()?;
+                let mtime = FileTime::from_last_modification_time(&metadata);
+                Some((mtime, metadata.len(), entry.path()))
+            })
+            .collect::<Vec<_>>();
+        let mut size = entries.iter().map(|(_, len, _)| len).sum::<u64>();
+        entries.sort_unstable();
+
+        let (mut count, mut freed) = (0, 0);
+        for (_, len, path) in &entries {
+            if size <= max_size {
+                break;
+            }
+            fs::remove_file(path).map_err(|err| {
+                [
+                    "Cannot delete file ",
+                    path.to_string_lossy().escape().as_str(),
+                    ". ",
+                    err.to_string().as_str(),
+                ]
+                .join("")
+            })?;
+            size -= len;
+            freed += len;
+            count += 1;
+        }
+        Ok((count, freed))
+    }
+
+    fn entry(&self, key: &str, extension: &str) -> PathBuf {
+        self.dir.join([key, ".", extension].join(""))
+    }
+
+    fn create_dir(&self) -> Result<(), String> {
+        fs::create_dir_all(&self.dir).map_err(|err| {
+            [
+                "Cannot create directory ",
+                self.dir.to_string_lossy().escape().as_str(),
+                ".\n",
+                err.to_string().as_str(),
+            ]
+            .join("")
+        })
+    }
+}
+
+fn touch(path: &Path) {
+    let _ = filetime::set_file_mtime(path, FileTime::now());
+}
+
+fn write_error(path: &Path, err: std::io::Error) -> String {
+    [
+        "Cannot write to file ",
+        path.to_string_lossy().escape().as_str(),
+        ". ",
+        err.to_string().as_str(),
+    ]
+    .join("")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::helpers::TempDir;
+
+    #[test]
+    fn prune_oldest_first() {
+        let dir = TempDir::new("store");
+        let store = Store::new(dir.0.to_str().unwrap());
+        for (i, key) in ["a", "b", "c"].iter().enumerate() {
+            store.put_frontmatter(key, "0123456789").unwrap();
+            let mtime = FileTime::from_unix_time(1_000_000 + i as i64, 0);
+            filetime::set_file_mtime(store.entry(key, "frontmatter"), mtime).unwrap();
+        }
+        // Reading 'a' makes it the most recently used
+        assert_eq!(store.get_frontmatter("a").as_deref(), Some("0123456789"));
+
+        assert_eq!(store.prune(20).unwrap(), (1, 10));
+        assert!(store.get_frontmatter("b").is_none());
+        assert!(store.get_frontmatter("c").is_some());
+        assert_eq!(store.prune(0).unwrap(), (2, 20));
+    }
+
+    #[test]
+    fn key() {
+        let api = FileApi::from_filename("config/api/", "adoc").unwrap();
+        let key = |context: &Context, body: &[&str]| Store::key(&api, &["compile"], context, body);
+        let context = Context {
+            lang: "en",
+            post_id: "a",
+            source_path: "published/a.adoc",
+            other_langs: "jp",
+            cache_dir: ".cache",
+            lines: None,
+        };
+        let base = key(&context, &["Hello"]);
+        assert_eq!(base.len(), 64);
+        assert!(base.chars().all(|c| c.is_ascii_hexdigit()));
+
+        // Renaming or moving the post, or moving the cache dir, still hits
+        let moved = Context {
+            post_id: "b",
+            source_path: "drafts/b.adoc",
+            cache_dir: "other-cache",
+            ..context
+        };
+        assert_eq!(key(&moved, &["Hello"]), base);
+
+        let other_lang = Context {
+            lang: "jp",
+            ..context
+        };
+        assert_ne!(key(&other_lang, &["Hello"]), base);
+        assert_ne!(key(&context, &["Hello!"]), base);
+        // Field boundaries are part of the hash
+        assert_ne!(key(&context, &["Hel", "lo"]), base);
+    }
+}
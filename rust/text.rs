@@ -0,0 +1,245 @@
+// Plain-text helpers for working on the compiled HTML partials
+// Used for the metadata we derive from the post body (e.g. reading time)
+
+// Rough reading speeds, CJK is counted per character rather than per word
+const WORDS_PER_MINUTE: usize = 200;
+const CJK_CHARS_PER_MINUTE: usize = 400;
+
+// Han, kana, and their extensions. Hangul is space-delimited so is not here
+pub fn is_cjk(c: char) -> bool {
+    matches!(c,
+        '\u{3040}'..='\u{30FF}'   // Hiragana and Katakana
+        | '\u{31F0}'..='\u{31FF}' // Katakana phonetic extensions
+        | '\u{3400}'..='\u{4DBF}' // CJK Unified Ideographs Extension A
+        | '\u{4E00}'..='\u{9FFF}' // CJK Unified Ideographs
+        | '\u{F900}'..='\u{FAFF}' // CJK Compatibility Ideographs
+        | '\u{FF66}'..='\u{FF9F}' // Halfwidth Katakana
+        | '\u{20000}'..='\u{2FA1F}' // Extensions B to F and supplement
+    )
+}
+
+// Removes the tags and decodes the common entities
+// Not a full HTML parser, but the partials come from markup compilers
+pub fn strip_tags(html: &str) -> String {
+    let mut output = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(open) = rest.find('<') {
+        output.push_str(&rest[..open]);
+        // Tags separate words, e.g. "<td>a</td><td>b</td>"
+        output.push(' ');
+        rest = match rest[open..].find('>') {
+            Some(close) => &rest[open + close + '>'.len_utf8()..],
+            None => "",
+        };
+    }
+    output.push_str(rest);
+    decode_entities(output.as_str())
+}
+
+pub fn escape_html(buffer: &mut String, text: &str) {
+    for c in text.chars() {
+        match c {
+            '&' => buffer.push_str("&amp;"),
+            '<' => buffer.push_str("&lt;"),
+            '>' => buffer.push_str("&gt;"),
+            '"' => buffer.push_str("&quot;"),
+            c => buffer.push(c),
+        }
+    }
+}
+
+pub fn decode_entities(text: &str) -> String {
+    if !text.contains('&') {
+        return text.to_string();
+    }
+    let mut output = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(amp) = rest.find('&') {
+        output.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+        let entity_end = rest.find(';').filter(|end| *end <= 10);
+        let decoded = entity_end.and_then(|end| match &rest[1..end] {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some(' '),
+            num if num.starts_with("#x") || num.starts_with("#X") => {
+                u32::from_str_radix(&num[2..], 16)
+                    .ok()
+                    .and_then(std::char::from_u32)
+            }
+            num if num.starts_with('#') => num[1..].parse().ok().and_then(std::char::from_u32),
+            _ => None,
+        });
+        match (decoded, entity_end) {
+            (Some(c), Some(end)) => {
+                output.push(c);
+                rest = &rest[end + ';'.len_utf8()..];
+            }
+            _ => {
+                output.push('&');
+                rest = &rest['&'.len_utf8()..];
+            }
+        }
+    }
+    output.push_str(rest);
+    output
+}
+
+// Returns (words, CJK characters)
+pub fn count_words(text: &str) -> (usize, usize) {
+    let mut words = 0;
+    let mut cjk = 0;
+    let mut is_in_word = false;
+    for c in text.chars() {
+        if is_cjk(c) {
+            cjk += 1;
+            is_in_word = false;
+        } else if c.is_alphanumeric() {
+            if !is_in_word {
+                words += 1;
+            }
+            is_in_word = true;
+        } else if c.is_whitespace() || !matches!(c, '\'' | '-' | '’') {
+            // Keep contractions and hyphenated words as one word
+            is_in_word = false;
+        }
+    }
+    (words, cjk)
+}
+
+// Rounded up, so any text at all is at least one minute
+pub fn reading_minutes((words, cjk): (usize, usize)) -> usize {
+    // words / WPM + cjk / CPM, but in integers
+    let scaled = words * CJK_CHARS_PER_MINUTE + cjk * WORDS_PER_MINUTE;
+    let per_minute = WORDS_PER_MINUTE * CJK_CHARS_PER_MINUTE;
+    scaled.div_ceil(per_minute)
+}
+
+// The inner HTML of the first '<p>' (or '<p class=...>')
+pub fn first_paragraph(html: &str) -> Option<&str> {
+    let mut rest = html;
+    while let Some(open) = rest.find("<p") {
+        rest = &rest[open + "<p".len()..];
+        if rest.starts_with('>') || rest.starts_with(char::is_whitespace) {
+            let start = rest.find('>')? + '>'.len_utf8();
+            let close = rest[start..].find("</p>").unwrap_or(rest.len() - start);
+            return Some(&rest[start..start + close]);
+        }
+    }
+    None
+}
+
+// Single line, so it is safe for the line-based caches
+pub fn collapse_whitespace(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+// Approximates graphemes by not counting combining characters
+// Appends an ellipsis if truncated
+pub fn truncate_graphemes(text: &str, max: usize) -> String {
+    let mut count = 0;
+    for (i, c) in text.char_indices() {
+        if !is_combining(c) {
+            if count == max {
+                return [text[..i].trim_end(), "…"].join("");
+            }
+            count += 1;
+        }
+    }
+    text.to_string()
+}
+
+fn is_combining(c: char) -> bool {
+    matches!(c,
+        '\u{0300}'..='\u{036F}'   // Combining diacritical marks
+        | '\u{1AB0}'..='\u{1AFF}' // Extended combining diacritical marks
+        | '\u{200C}'..='\u{200D}' // Zero width (non-)joiner, used in emoji
+        | '\u{20D0}'..='\u{20FF}' // Combining marks for symbols
+        | '\u{3099}'..='\u{309A}' // Kana voiced sound marks
+        | '\u{FE00}'..='\u{FE0F}' // Variation selectors
+        | '\u{FE20}'..='\u{FE2F}' // Combining half marks
+        | '\u{1F3FB}'..='\u{1F3FF}' // Emoji skin tones
+    )
+}
+
+// For the search index, lowercased and deduplicated
+// Runs of CJK are split into overlapping bigrams as they have no spaces, so
+// the client-side search must tokenise the query the same way
+pub fn tokenise(text: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut word = String::new();
+    let mut cjk_run = Vec::new();
+    for c in text.chars().chain(std::iter::once(' ')) {
+        if is_cjk(c) {
+            push_word(&mut tokens, &mut word);
+            cjk_run.push(c);
+        } else {
+            push_bigrams(&mut tokens, &mut cjk_run);
+            if c.is_alphanumeric() {
+                word.extend(c.to_lowercase());
+            } else {
+                push_word(&mut tokens, &mut word);
+            }
+        }
+    }
+    tokens.sort_unstable();
+    tokens.dedup();
+    tokens
+}
+
+fn push_word(tokens: &mut Vec<String>, word: &mut String) {
+    if !word.is_empty() {
+        tokens.push(std::mem::take(word));
+    }
+}
+
+fn push_bigrams(tokens: &mut Vec<String>, cjk_run: &mut Vec<char>) {
+    match cjk_run.len() {
+        0 => {}
+        1 => tokens.push(cjk_run[0].to_string()),
+        _ => tokens.extend(cjk_run.windows(2).map(|pair| pair.iter().collect())),
+    }
+    cjk_run.clear();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn count_test() {
+        let html = "<p>The quick, brown fox&#39;s den</p><p>isn&apos;t well-known.</p>";
+        let text = strip_tags(html);
+        assert_eq!(text.trim(), "The quick, brown fox's den  isn't well-known.");
+        assert_eq!(count_words(text.as_str()), (7, 0));
+
+        let text = strip_tags("<p>中文的文字在這裡。Rust は速い</p>");
+        assert_eq!(count_words(text.as_str()), (1, 11));
+        assert_eq!(
+            strip_tags("a &amp b &unknown; <unclosed"),
+            "a &amp b &unknown;  "
+        );
+
+        assert_eq!(reading_minutes((0, 0)), 0);
+        assert_eq!(reading_minutes((1, 0)), 1);
+        assert_eq!(reading_minutes((200, 0)), 1);
+        assert_eq!(reading_minutes((201, 0)), 2);
+        assert_eq!(reading_minutes((100, 200)), 1);
+        assert_eq!(reading_minutes((0, 401)), 2);
+
+        let html = "<div><pre>code</pre><p class=\"lead\">First\n para</p><p>2</p>";
+        assert_eq!(first_paragraph(html), Some("First\n para"));
+        assert_eq!(first_paragraph("<pre>only</pre>"), None);
+        assert_eq!(collapse_whitespace(" a\n  b "), "a b");
+        assert_eq!(truncate_graphemes("cafe\u{301} au lait", 5), "cafe\u{301}…");
+        assert_eq!(truncate_graphemes("短い", 5), "短い");
+
+        assert_eq!(
+            tokenise("Rust は速い。The rust BOOK 中文"),
+            vec!["book", "rust", "the", "は速", "中文", "速い"]
+        );
+    }
+}
@@ -0,0 +1,163 @@
+// Profiling of the compile pipeline (--timings)
+// Records how long each stage takes per post/view/handler process so that we
+// can see what makes builds slow before deciding what to make async
+
+use crate::traits::ShellEscape;
+use std::cmp::Reverse;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stage {
+    Read,
+    Split,
+    Frontmatter,
+    Compile,
+    Highlight,
+    Link,
+    CacheWrite,
+}
+
+impl Stage {
+    const ALL: [Stage; 7] = [
+        Stage::Read,
+        Stage::Split,
+        Stage::Frontmatter,
+        Stage::Compile,
+        Stage::Highlight,
+        Stage::Link,
+        Stage::CacheWrite,
+    ];
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Stage::Read => "read",
+            Stage::Split => "split",
+            Stage::Frontmatter => "frontmatter",
+            Stage::Compile => "compile",
+            Stage::Highlight => "highlight",
+            Stage::Link => "link",
+            Stage::CacheWrite => "cache_write",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Timings {
+    is_enabled: bool,
+    entries: Vec<(Stage, String, Duration)>,
+}
+
+impl Timings {
+    pub fn new(is_enabled: bool) -> Self {
+        Self {
+            is_enabled,
+            entries: Vec::new(),
+        }
+    }
+
+    // 'label' is a closure so we only pay for the formatting with --timings
+    pub fn time<T, F: FnOnce() -> T, L: FnOnce() -> String>(
+        &mut self,
+        stage: Stage,
+        label: L,
+        to_time: F,
+    ) -> T {
+        if self.is_enabled {
+            let start = Instant::now();
+            let output = to_time();
+            self.entries.push((stage, label(), start.elapsed()));
+            output
+        } else {
+            to_time()
+        }
+    }
+
+    // Stage totals followed by every entry, both slowest first
+    pub fn report(&self) -> String {
+        let mut totals = Stage::ALL
+            .iter()
+            .map(|stage| {
+                let total = self
+                    .entries
+                    .iter()
+                    .filter(|(s, _, _)| s == stage)
+                    .map(|(_, _, duration)| *duration)
+                    .sum::<Duration>();
+                (*stage, total)
+            })
+            .collect::<Vec<_>>();
+        totals.sort_by_key(|(_, total)| Reverse(*total));
+
+        let mut sorted = self.entries.iter().collect::<Vec<_>>();
+        sorted.sort_by_key(|(_, _, duration)| Reverse(*duration));
+
+        let mut buffer = String::from("=== Timings (per stage) ===\n");
+        for (stage, total) in &totals {
+            buffer
+                .push_str(format!("{:>10.3}s  {}\n", total.as_secs_f64(), stage.as_str()).as_str());
+        }
+        buffer.push_str("=== Timings (slowest first) ===\n");
+        for (stage, label, duration) in sorted {
+            buffer.push_str(
+                format!(
+                    "{:>10.3}s  {:<12} {}\n",
+                    duration.as_secs_f64(),
+                    stage.as_str(),
+                    label
+                )
+                .as_str(),
+            );
+        }
+        buffer
+    }
+
+    // @FORMAT timings csv
+    // Last column is the label (only column that could have commas as data)
+    pub fn to_csv(&self) -> String {
+        let mut sorted = self.entries.iter().collect::<Vec<_>>();
+        sorted.sort_by_key(|(_, _, duration)| Reverse(*duration));
+
+        let mut buffer = String::from("stage,microseconds,label\n");
+        for (stage, label, duration) in sorted {
+            buffer.push_str(stage.as_str());
+            buffer.push(',');
+            buffer.push_str(duration.as_micros().to_string().as_str());
+            buffer.push(',');
+            buffer.push_str(label.replace('\n', " ").as_str());
+            buffer.push('\n');
+        }
+        buffer
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.is_enabled
+    }
+}
+
+// For labelling entries with the post, view, and handler process
+pub fn label(id: &str, lang: &str, process: &str) -> String {
+    [id.escape().as_str(), " ", lang, " (", process, ")"].join("")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn report_is_sorted() {
+        let mut timings = Timings::new(true);
+        timings.time(Stage::Link, || "fast".to_string(), || ());
+        timings.time(
+            Stage::Compile,
+            || "slow".to_string(),
+            || std::thread::sleep(Duration::from_millis(5)),
+        );
+        let report = timings.report();
+        assert!(report.find("slow").unwrap() < report.find("fast").unwrap());
+        assert_eq!(timings.to_csv().lines().count(), 3);
+
+        let mut disabled = Timings::new(false);
+        assert_eq!(disabled.time(Stage::Read, || unreachable!(), || 1), 1);
+        assert!(disabled.entries.is_empty());
+    }
+}
@@ -0,0 +1,249 @@
+// The 'translation-report' subcommand
+// Lists which languages each post has, sections that only some of its views
+// have, and how different in size its views are. With '--site-langs', also
+// flags the posts that are missing (or have extra) languages.
+
+use crate::{
+    fileapi::{FileApi, HandlerMap, Limits},
+    helpers::PathReadMetadata,
+    include::{self, Origins},
+    post::{Label, LangGroups, Post, SplitByLabel, ALL_LANG_REPR},
+    text,
+    traits::{ResultExt, ShellEscape},
+};
+use std::{collections::HashMap, fs, path::Path};
+
+pub fn report(
+    api_dir: &str,
+    handler_map: Option<&str>,
+    default_lang: Option<&str>,
+    lang_groups: Option<&str>,
+    site_langs: Option<&str>,
+    input_list: &[PathReadMetadata],
+) -> String {
+    let site_langs = site_langs
+        .map(|langs| langs.split_whitespace().collect::<Vec<_>>())
+        .unwrap_or_default();
+
+    let handler_map = HandlerMap::read(handler_map).or_die(1);
+    let lang_groups = LangGroups::parse(lang_groups).or_die(1);
+    let mut comments = HashMap::new();
+    let mut buffer = String::new();
+    let mut flagged_count = 0;
+    for path in input_list {
+        if !comments.contains_key(path.extension) {
+            let handler = handler_map.resolve(path.extension);
+            let api = FileApi::from_handler(api_dir, &handler, Limits::default()).or_die(1);
+            comments.insert(path.extension, api.comment().or_die(1));
+        }
+        let comment = comments.get(path.extension).unwrap().as_str();
+        let text = fs::read_to_string(path.path)
+            .map_err(|err| {
+                [
+                    "Cannot read ",
+                    path.path.to_string_lossy().escape().as_str(),
+                    ". ",
+                    err.to_string().as_str(),
+                ]
+                .join("")
+            })
+            .or_die(1);
+        let (text, origins) = match include::splice(text.as_str(), comment, path.path).or_die(1) {
+            Some((spliced, _, origins)) => (spliced, origins),
+            None => (text, Origins::default()),
+        };
+
+        let mut post = Post::new(text.as_str(), comment, default_lang, &lang_groups)
+            .map_err(|err| {
+                err.with_source_row(|row| {
+                    let (file, row) = origins.locate(row, path.path);
+                    (file.to_string_lossy(), row)
+                })
+            })
+            .or_die(1);
+        post.origins = origins;
+        let name = [path.stem, ".", path.extension].join("");
+        if report_post(
+            &mut buffer,
+            name.as_str(),
+            path.path,
+            &post,
+            text.as_str(),
+            comment,
+            &site_langs,
+        ) {
+            flagged_count += 1;
+        }
+    }
+
+    if !site_langs.is_empty() {
+        buffer.push_str(
+            format!(
+                "{} of {} posts differ from the site languages: {}\n",
+                flagged_count,
+                input_list.len(),
+                site_langs.join(" "),
+            )
+            .as_str(),
+        );
+    }
+    buffer
+}
+
+// Returns true if the languages of 'post' differ from 'site_langs'
+fn report_post(
+    buffer: &mut String,
+    name: &str,
+    path: &Path,
+    post: &Post,
+    text: &str,
+    comment: &str,
+    site_langs: &[&str],
+) -> bool {
+    let langs = &post.lang_list;
+    buffer.push_str(name);
+    buffer.push(':');
+    for lang in langs {
+        buffer.push(' ');
+        buffer.push_str(lang);
+    }
+    buffer.push('\n');
+
+    // Languages
+    let mut is_flagged = false;
+    if !site_langs.is_empty() {
+        let missing = site_langs.iter().filter(|l| !langs.contains(l));
+        let extra = langs.iter().filter(|l| !site_langs.contains(l));
+        let missing = missing.copied().collect::<Vec<_>>();
+        let extra = extra.copied().collect::<Vec<_>>();
+        is_flagged |= push_list(buffer, "  missing:", &missing);
+        is_flagged |= push_list(buffer, "  not a site language:", &extra);
+    }
+
+    // Sections exclusive to a subset of languages
+    // Same labelling as 'Post::new()', the label applies to the section after
+    let mut cur_langs = ALL_LANG_REPR[0];
+    for (section, next_langs, _, _) in SplitByLabel::new(text, comment).map_while(Result::ok) {
+        let label = Label::parse(cur_langs, &mut Vec::new(), &post.lang_groups).unwrap_or_default();
+        cur_langs = next_langs;
+        if label.is_all() {
+            continue;
+        }
+        let (only, without): (Vec<&str>, Vec<&str>) = langs.iter().partition(|l| label.includes(l));
+        if without.is_empty() || section.trim().is_empty() {
+            continue;
+        }
+
+        let offset = section.as_ptr() as usize - text.as_ptr() as usize;
+        let start = text[..offset].matches('\n').count() + 1;
+        let close = start + section.trim_end().matches('\n').count();
+        // Rows in the post file, or in the file it includes at that row
+        let at = |row| match post.origins.locate(row, path) {
+            (file, row) if file == path => row.to_string(),
+            (file, row) => [
+                file.to_string_lossy().escape().as_str(),
+                ":",
+                &row.to_string(),
+            ]
+            .join(""),
+        };
+        if start == close {
+            buffer.push_str(format!("  line {}: only", at(start)).as_str());
+        } else {
+            buffer.push_str(format!("  lines {}-{}: only", at(start), at(close)).as_str());
+        }
+        for lang in &only {
+            buffer.push(' ');
+            buffer.push_str(lang);
+        }
+        push_list(buffer, ", not in", &without);
+    }
+
+    // Size, as in 'text::count_words()', relative to the largest view
+    if post.views.len() > 1 {
+        let sizes = post
+            .views
+            .iter()
+            .map(|view| {
+                let counts = view.body.iter().map(|section| text::count_words(section));
+                (
+                    view.lang.unwrap_or(""),
+                    counts.map(|(w, c)| w + c).sum::<usize>(),
+                )
+            })
+            .collect::<Vec<_>>();
+        let max = sizes.iter().map(|(_, size)| *size).max().unwrap_or(0);
+        buffer.push_str("  size:");
+        for (i, (lang, size)) in sizes.iter().enumerate() {
+            let percent = (size * 100).checked_div(max).unwrap_or(100);
+            let separator = if i > 0 { ", " } else { " " };
+            buffer.push_str(format!("{}{} {} ({}%)", separator, lang, size, percent).as_str());
+        }
+        buffer.push('\n');
+    }
+    is_flagged
+}
+
+// Returns true if anything was pushed
+fn push_list(buffer: &mut String, heading: &str, list: &[&str]) -> bool {
+    if list.is_empty() {
+        return false;
+    }
+    buffer.push_str(heading);
+    for entry in list {
+        buffer.push(' ');
+        buffer.push_str(entry);
+    }
+    buffer.push('\n');
+    true
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn report_test() {
+        let text = "\
+shared
+//api_set_lang: en jp
+both
+//api_set_lang: en
+only en
+more en
+//api_set_lang: ALL
+shared again
+";
+        let post = Post::new(text, "//", None, &LangGroups::default()).unwrap();
+        let mut buffer = String::new();
+        assert!(report_post(
+            &mut buffer,
+            "a.adoc",
+            Path::new("a.adoc"),
+            &post,
+            text,
+            "//",
+            &["en", "jp", "zh"]
+        ));
+        assert_eq!(
+            buffer,
+            "\
+a.adoc: en jp
+  missing: zh
+  lines 5-6: only en, not in jp
+  size: en 8 (100%), jp 4 (50%)
+"
+        );
+
+        let mut buffer = String::new();
+        assert!(!report_post(
+            &mut buffer,
+            "a.adoc",
+            Path::new("a.adoc"),
+            &post,
+            text,
+            "//",
+            &[]
+        ));
+    }
+}